@@ -32,6 +32,17 @@ rm -f $HDR_OBJ_FILES
 	-type f) >> "$HDR_OBJ_FILES"
 (cd $objtree; find tools/ -executable \
         -type f) >> "$HDR_OBJ_FILES"
+# Rust metadata and proc macros, for kernels built with CONFIG_RUST
+(cd $objtree; find rust -maxdepth 1 \( -name '*.rmeta' -o -name '*.so' \
+	-o -name 'target.json' \) -type f 2>/dev/null) >> "$HDR_OBJ_FILES"
+# module signing certificate, for kernels that enforce signatures. The
+# private key is only shipped on request, for throwaway test kernels.
+(cd $objtree; find certs -name 'signing_key.x509' -type f 2>/dev/null) \
+	>> "$HDR_OBJ_FILES"
+if [ -n "$SIGNING_KEY" ]; then
+	(cd $objtree; find certs -name 'signing_key.pem' -type f 2>/dev/null) \
+		>> "$HDR_OBJ_FILES"
+fi
 
 mkdir -p "$DEST_DIR"
 
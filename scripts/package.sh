@@ -5,6 +5,9 @@ trap 'echo FAILED COMMAND: $previous_command' EXIT
 
 : ${ARCH=x86}
 : ${KERNEL:=4.19.237}
+: ${SRCARCH:=$([ $ARCH = i386 ] && echo x86 || echo $ARCH)}
+# Rust support needs an LLVM build
+if [ -n "$RUST" ]; then LLVM=1; fi
 BUILD_DIR=/tmp/package-linux-$KERNEL
 SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
 
@@ -38,7 +41,7 @@ popd
 
 # Build the kernel image
 pushd $BUILD_DIR/linux-$KERNEL
-make ARCH=$ARCH defconfig
+make ARCH=$ARCH ${LLVM:+LLVM=$LLVM} defconfig
 #make ARCH=$ARCH kvmconfig | make ARCH=$ARCH kvm_guest.config
 echo "
 CONFIG_CRYPTO_RSA=y
@@ -58,18 +61,28 @@ CONFIG_DEBUG_OBJECTS_RCU_HEAD=y
 CONFIG_KASAN=y
 CONFIG_KASAN_INLINE=y
 
+# Report panics to qemu, PCI on aarch64 and riscv64
+CONFIG_PVPANIC=y
+CONFIG_PVPANIC_MMIO=y
+CONFIG_PVPANIC_PCI=y
+
 # Required for Debian Stretch
 CONFIG_CONFIGFS_FS=y
 CONFIG_SECURITYFS=y" >> .config
+if [ -n "$RUST" ]; then echo "CONFIG_RUST=y" >> .config; fi
 sort .config | uniq -u >> .config2 && mv .config2 .config
 #make ARCH=$ARCH olddefconfig
-make ARCH=$ARCH -j`nproc`
-cp arch/x86_64/boot/bzImage $BUILD_DIR/bzImage-linux-$KERNEL
+make ARCH=$ARCH ${LLVM:+LLVM=$LLVM} -j`nproc`
+case $ARCH in
+    x86|i386) cp arch/x86/boot/bzImage $BUILD_DIR/bzImage-linux-$KERNEL ;;
+    *) cp arch/$ARCH/boot/Image $BUILD_DIR/Image-linux-$KERNEL-$ARCH ;;
+esac
+cp vmlinux $BUILD_DIR/vmlinux-linux-$KERNEL
 popd
 
 # Package the headers and module information
 pushd $BUILD_DIR
-KCONFIG_CONFIG=.config SRCARCH=$ARCH objtree=$BUILD_DIR/linux-$KERNEL/ srctree=$BUILD_DIR/linux-$KERNEL/ $SCRIPT_DIR/module_headers_install.sh
+KCONFIG_CONFIG=.config SRCARCH=$SRCARCH objtree=$BUILD_DIR/linux-$KERNEL/ srctree=$BUILD_DIR/linux-$KERNEL/ $SCRIPT_DIR/module_headers_install.sh
 cd linux-modules-headers
 tar -czvf ../linux-$KERNEL-headers.tar.gz *
 popd
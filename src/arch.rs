@@ -0,0 +1,129 @@
+use serde::Deserialize;
+
+/// Guest architecture of a kernel under test
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    #[default]
+    #[serde(alias = "x86")]
+    X86_64,
+    #[serde(alias = "x86_32")]
+    I386,
+    #[serde(alias = "arm64")]
+    Aarch64,
+    #[serde(alias = "riscv")]
+    Riscv64,
+}
+
+impl Arch {
+    /// The architecture nixmodule itself was compiled for
+    pub fn host() -> Option<Self> {
+        match std::env::consts::ARCH {
+            "x86_64" => Some(Self::X86_64),
+            "x86" => Some(Self::I386),
+            "aarch64" => Some(Self::Aarch64),
+            "riscv64" => Some(Self::Riscv64),
+            _ => None,
+        }
+    }
+
+    /// Test if a guest of this architecture can run natively
+    /// on the host, i.e. with hardware acceleration.
+    pub fn is_native(&self) -> bool {
+        match (Self::host(), self) {
+            (Some(Self::X86_64), Self::I386) => true,
+            (Some(host), guest) => host == *guest,
+            (None, _) => false,
+        }
+    }
+
+    /// Default qemu system emulator for this architecture
+    pub fn runner(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "qemu-system-x86_64",
+            Self::I386 => "qemu-system-i386",
+            Self::Aarch64 => "qemu-system-aarch64",
+            Self::Riscv64 => "qemu-system-riscv64",
+        }
+    }
+
+    /// Machine and cpu selection
+    pub fn machine_args(&self, kvm: bool) -> Vec<&'static str> {
+        match self {
+            Self::X86_64 | Self::I386 => vec![],
+            Self::Aarch64 if kvm => vec!["-M", "virt", "-cpu", "host"],
+            Self::Aarch64 => vec!["-M", "virt", "-cpu", "cortex-a57"],
+            Self::Riscv64 => vec!["-M", "virt"],
+        }
+    }
+
+    /// Serial console the kernel should log to
+    pub fn console(&self) -> &'static str {
+        match self {
+            Self::X86_64 | Self::I386 | Self::Riscv64 => "ttyS0",
+            Self::Aarch64 => "ttyAMA0",
+        }
+    }
+
+    /// Kernel arguments enabling early boot output
+    pub fn earlycon(&self) -> &'static str {
+        match self {
+            Self::X86_64 | Self::I386 => "earlyprintk=serial",
+            Self::Aarch64 | Self::Riscv64 => "earlycon",
+        }
+    }
+
+    /// Emulated network card model
+    pub fn nic(&self) -> &'static str {
+        match self {
+            Self::X86_64 | Self::I386 => "e1000",
+            Self::Aarch64 | Self::Riscv64 => "virtio-net-pci",
+        }
+    }
+
+    /// Paravirtualized panic device reporting guest panics to qemu
+    pub fn pvpanic(&self) -> &'static str {
+        match self {
+            Self::X86_64 | Self::I386 => "pvpanic",
+            Self::Aarch64 | Self::Riscv64 => "pvpanic-pci",
+        }
+    }
+
+    /// Arguments attaching the disk image to the guest
+    pub fn drive_args(&self, path: &str, format: &str) -> Vec<String> {
+        match self {
+            Self::X86_64 | Self::I386 => {
+                vec!["-drive".into(), format!("file={},format={}", path, format)]
+            }
+            Self::Aarch64 | Self::Riscv64 => vec![
+                "-drive".into(),
+                format!("file={},format={},if=none,id=hd0", path, format),
+                "-device".into(),
+                "virtio-blk-pci,drive=hd0".into(),
+            ],
+        }
+    }
+
+    /// Value of ARCH= expected by Kbuild
+    pub fn kbuild(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86",
+            Self::I386 => "i386",
+            Self::Aarch64 => "arm64",
+            Self::Riscv64 => "riscv",
+        }
+    }
+
+    /// Default CROSS_COMPILE prefix when building on a foreign host
+    pub fn cross_compile(&self) -> Option<&'static str> {
+        if self.is_native() {
+            return None;
+        }
+        match self {
+            Self::X86_64 => Some("x86_64-linux-gnu-"),
+            Self::I386 => Some("i686-linux-gnu-"),
+            Self::Aarch64 => Some("aarch64-linux-gnu-"),
+            Self::Riscv64 => Some("riscv64-linux-gnu-"),
+        }
+    }
+}
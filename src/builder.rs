@@ -1,44 +1,738 @@
+use crate::diagnostics::{Diagnostic, Severity};
 use crate::errors::NixModuleError::*;
-use crate::utils::print_output;
-use crate::KConfig;
+use crate::utils::{is_build_output, print_output};
+use crate::{KConfig, Module};
+use colored::*;
+use serde::Deserialize;
+use std::collections::HashSet;
 use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub struct ModuleBuilder;
 
+/// Language a module is written in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleKind {
+    #[default]
+    C,
+    Rust,
+}
+
+/// Trailer sign-file appends to a signed module
+const MODULE_SIG_MAGIC: &[u8] = b"~Module signature appended~\n";
+
+/// Result of a successful build
+pub struct Build {
+    // Paths of the built .ko files
+    pub modules: Vec<PathBuf>,
+
+    // Warnings of every object, including those not rebuilt this time
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Target of a Kbuild short log line such as `  CC [M]  /path/hello.o`,
+/// "modpost" for `  MODPOST Module.symvers`. None for other lines.
+fn unit(line: &str) -> Option<String> {
+    if !line.starts_with("  ") {
+        return None;
+    }
+    let mut tokens = line.split_whitespace();
+    let tag = tokens.next()?;
+    if !tag.starts_with(|c: char| c.is_ascii_uppercase())
+        || !tag
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+    {
+        return None;
+    }
+    let target = tokens.find(|t| *t != "[M]")?;
+    match tag {
+        "MODPOST" => Some("modpost".to_string()),
+        _ if target.ends_with(".o") => Some(target.to_string()),
+        _ => None,
+    }
+}
+
+/// Split make's output by the object or modpost step it belongs to.
+/// Lines before the first step, and of other build systems, are
+/// returned separately.
+fn split_units(log: &str) -> (Vec<(String, String)>, String) {
+    let mut units: Vec<(String, String)> = Vec::new();
+    let mut unattributed = String::new();
+    for line in log.lines() {
+        if let Some(unit) = unit(line) {
+            units.retain(|(u, _)| *u != unit);
+            units.push((unit, String::new()));
+            continue;
+        }
+        let output = match units.last_mut() {
+            Some((_, output)) => output,
+            None => &mut unattributed,
+        };
+        output.push_str(line);
+        output.push('\n');
+    }
+    (units, unattributed)
+}
+
+/// Every cached `.*.warnings` file below `dir`
+fn warning_caches(dir: &Path, res: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if path.is_dir() {
+            warning_caches(&path, res)?;
+        } else if name.starts_with('.') && name.ends_with(".warnings") {
+            res.push(path);
+        }
+    }
+    Ok(())
+}
+
 impl ModuleBuilder {
+    /// Name the kernel will register a built module under
+    pub fn modname(ko: &Path) -> Option<String> {
+        Some(Self::normalize(ko.file_stem()?.to_str()?))
+    }
+
+    /// Kbuild replaces dashes and commas in module names with underscores
+    pub fn normalize(name: &str) -> String {
+        name.replace(['-', ','], "_")
+    }
+
+    /// Mirror the module sources into a build directory, copying only
+    /// what changed so Kbuild can rebuild incrementally. Sources deleted
+    /// since the last build are removed, Kbuild's outputs are kept.
+    fn sync(src: &Path, dst: &Path, skip: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(dst)?;
+        let mut synced = HashSet::new();
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let path = entry.path();
+            if is_build_output(&path) || skip.iter().any(|s| path.starts_with(s)) {
+                continue;
+            }
+
+            // Linked files are copied, linked directories may loop
+            let meta = match entry.file_type()?.is_symlink() {
+                true => match fs::metadata(&path) {
+                    Ok(meta) if meta.is_file() => meta,
+                    _ => {
+                        log_status!("Not following symlink {:?}", path);
+                        continue;
+                    }
+                },
+                false => entry.metadata()?,
+            };
+            let target = dst.join(entry.file_name());
+            synced.insert(entry.file_name());
+
+            if meta.is_dir() {
+                if target.is_file() {
+                    fs::remove_file(&target)?;
+                }
+                Self::sync(&path, &target, skip)?;
+                continue;
+            }
+            if target.is_dir() {
+                fs::remove_dir_all(&target)?;
+            }
+            let stale = match fs::metadata(&target) {
+                Ok(existing) => meta.modified()? > existing.modified()?,
+                Err(_) => true,
+            };
+            if stale {
+                fs::copy(&path, &target)?;
+            }
+        }
+
+        for entry in fs::read_dir(dst)? {
+            let entry = entry?;
+            let path = entry.path();
+            if synced.contains(&entry.file_name()) || is_build_output(&path) {
+                continue;
+            }
+            match entry.file_type()?.is_dir() {
+                true => fs::remove_dir_all(&path)?,
+                false => fs::remove_file(&path)?,
+            }
+
+            // Its object would otherwise keep reporting cached warnings
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                for output in [format!("{}.o", stem), format!(".{}.o.warnings", stem)] {
+                    let _ = fs::remove_file(dst.join(output));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Directory holding the module's sources
+    pub fn srcdir(module: &Module) -> Result<PathBuf, Box<dyn Error>> {
+        let dir = match module.source {
+            Some(ref dir) => PathBuf::from(shellexpand::tilde(dir).as_ref()),
+            None => std::env::current_dir()?,
+        };
+        Ok(fs::canonicalize(dir)?)
+    }
+
+    /// Split a NAME=VALUE definition, the value may itself contain '='
+    fn define(entry: &str) -> Result<(&str, &str), Box<dyn Error>> {
+        match entry.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => Ok((name.trim(), value)),
+            _ => {
+                log_error!("Invalid definition {:?}, expected NAME=VALUE", entry);
+                Err(BuildError.into())
+            }
+        }
+    }
+
+    /// Cross-compile prefix when the guest doesn't match the host
+    fn cross(kernel: &KConfig) -> Option<&str> {
+        kernel
+            .cross_compile
+            .as_deref()
+            .or(kernel.arch.cross_compile())
+    }
+
+    /// Compiler override and whether to build with the LLVM toolchain.
+    /// A kernel setting either takes precedence, and both come from the
+    /// same entry so a module's cc isn't mixed with a kernel's LLVM=1.
+    /// Rust modules always require LLVM.
+    fn toolchain<'a>(module: &'a Module, kernel: &'a KConfig) -> (Option<&'a str>, bool) {
+        let (cc, llvm) = match kernel.cc.is_some() || kernel.llvm.is_some() {
+            true => (kernel.cc.as_deref(), kernel.llvm),
+            false => (module.cc.as_deref(), module.llvm),
+        };
+        (cc, llvm.unwrap_or(false) || module.kind == ModuleKind::Rust)
+    }
+
+    /// Compiler the module is built with
+    fn compiler(module: &Module, kernel: &KConfig) -> String {
+        match Self::toolchain(module, kernel) {
+            (Some(cc), _) => cc.to_string(),
+            (None, true) => "clang".to_string(),
+            (None, false) => format!("{}gcc", Self::cross(kernel).unwrap_or_default()),
+        }
+    }
+
+    /// make set up with the toolchain, environment and
+    /// variables configured for a kernel, without a target
+    pub fn make(module: &Module, kernel: &KConfig) -> Result<Command, Box<dyn Error>> {
+        let mut make = Command::new("make");
+
+        let env = module
+            .build_defines
+            .iter()
+            .flatten()
+            .chain(module.env.iter())
+            .chain(kernel.env.iter());
+        for entry in env {
+            let (name, value) = Self::define(entry)?;
+            make.env(name, value);
+        }
+
+        if let Some(prefix) = Self::cross(kernel) {
+            make.arg(format!("CROSS_COMPILE={}", prefix));
+        }
+        make.arg(format!("ARCH={}", kernel.arch.kbuild()));
+
+        let (cc, llvm) = Self::toolchain(module, kernel);
+        if llvm {
+            make.arg("LLVM=1");
+        }
+        if let Some(cc) = cc {
+            make.arg(format!("CC={}", cc));
+        }
+        make.args(module.make_args.iter().chain(kernel.make_args.iter()));
+        for entry in module.make_vars.iter().chain(kernel.make_vars.iter()) {
+            let (name, value) = Self::define(entry)?;
+            make.arg(format!("{}={}", name, value));
+        }
+        Ok(make)
+    }
+
+    /// make invocation building the modules synced into `builddir`
+    pub fn command(
+        module: &Module,
+        kernel: &KConfig,
+        builddir: &Path,
+    ) -> Result<Command, Box<dyn Error>> {
+        let mut make = Self::make(module, kernel)?;
+
+        // Either hand over to the module's own Makefile,
+        // or invoke Kbuild on the build directory directly
+        match module.custom_make {
+            // Makefiles commonly pass M=$(PWD), which make takes from the
+            // environment rather than the directory it runs in
+            true => make
+                .current_dir(builddir)
+                .env("PWD", builddir)
+                .arg(format!("KERNEL={}", kernel.headers))
+                .arg(format!("TARGET={}-{}", module.name, kernel.version)),
+            false => make
+                .arg("-C")
+                .arg(&kernel.headers)
+                .arg(format!("M={}", builddir.display()))
+                .arg("modules"),
+        };
+        Ok(make)
+    }
+
+    /// Build the module for a kernel in its own build directory,
+    /// returning the built .ko files and the warnings raised. Sources
+    /// below `skip`, such as the run output, aren't copied over. The
+    /// output of make is kept as build.log in `kdir`, failed or not.
     pub fn build(
-        name: &str,
-        defines: &Option<Vec<String>>,
+        module: &Module,
         kernel: &KConfig,
-    ) -> Result<String, Box<dyn Error>> {
-        let builddir = std::env::var("PWD")?;
-        let mut make = Command::new("make");
+        builddir: &Path,
+        kdir: &Path,
+        skip: &[&Path],
+    ) -> Result<Build, Box<dyn Error>> {
+        fs::create_dir_all(builddir)?;
+        let builddir = fs::canonicalize(builddir)?;
+        let skip: Vec<PathBuf> = skip
+            .iter()
+            .filter_map(|p| fs::canonicalize(p).ok())
+            .chain(std::iter::once(builddir.clone()))
+            .collect();
+        Self::sync(&Self::srcdir(module)?, &builddir, &skip)?;
+        Self::check_compiler(kernel, &Self::compiler(module, kernel));
+        if module.kind == ModuleKind::Rust {
+            Self::check_rust(kernel)?;
+        }
 
-        if let Some(definition) = defines {
-            let mut tuples: Vec<(&str, &str)> = Vec::new();
-            for v in definition.iter() {
-                let mut inner = v.split('=');
-                let name = inner.next().ok_or(BuildError)?;
-                let value = inner.next().ok_or(BuildError)?;
-                tuples.push((name, value));
+        let mut make = Self::command(module, kernel, &builddir)?;
+        let res = make.output()?;
+        let log = String::from_utf8_lossy(&res.stdout).into_owned()
+            + &String::from_utf8_lossy(&res.stderr);
+        fs::create_dir_all(kdir)?;
+        fs::write(kdir.join("build.log"), &log)?;
+        let warnings = Self::warnings(&builddir, &log)?;
+        if !res.status.success() {
+            print_output(&log);
+            return Err(BuildError.into());
+        }
+
+        let modules = Self::modules(&builddir)?;
+        if modules.is_empty() {
+            log_error!("No modules found in {:?}", builddir.join("modules.order"));
+            return Err(BuildError.into());
+        }
+        Self::sign(kernel, &modules)?;
+        Ok(Build { modules, warnings })
+    }
+
+    /// Warnings of the whole module, not just of what make rebuilt this
+    /// time. The output for each object, and for modpost, is cached next
+    /// to it, and objects make skipped contribute their cached output.
+    fn warnings(builddir: &Path, log: &str) -> Result<Vec<Diagnostic>, Box<dyn Error>> {
+        let (units, unattributed) = split_units(log);
+        for (unit, output) in units {
+            let cache = match unit.as_str() {
+                "modpost" => builddir.join(".modpost.warnings"),
+                object => {
+                    let object = builddir.join(object);
+                    let Some(name) = object.file_name().and_then(|n| n.to_str()) else {
+                        continue;
+                    };
+                    object.with_file_name(format!(".{}.warnings", name))
+                }
+            };
+            if cache.starts_with(builddir) && cache.parent().is_some_and(Path::exists) {
+                fs::write(cache, output)?;
             }
-            make.envs(tuples);
         }
 
-        let res = make
-            .current_dir(&builddir)
-            .arg(format!("KERNEL={}", kernel.headers))
-            .arg(format!("TARGET={}-{}", name, kernel.version))
-            .output()?;
+        let mut caches = Vec::new();
+        warning_caches(builddir, &mut caches)?;
+        caches.sort();
+        let mut output = unattributed;
+        for cache in caches {
+            output += &fs::read_to_string(cache)?;
+        }
+        Ok(Diagnostic::parse_all(&output)
+            .into_iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .collect())
+    }
 
-        match res.status.success() {
-            true => Ok(format!("{}/{}-{}.ko", builddir, name, kernel.version)),
-            false => {
-                print_output(std::str::from_utf8(&res.stdout)?);
-                print_output(std::str::from_utf8(&res.stderr)?);
-                Err(BuildError.into())
+    /// Value of an option in the kernel's .config, unquoted
+    fn config(headers: &Path, option: &str) -> Option<String> {
+        let config = fs::read_to_string(headers.join(".config")).ok()?;
+        config
+            .lines()
+            .filter_map(|l| l.split_once('='))
+            .find(|(name, _)| *name == option)
+            .map(|(_, value)| value.trim_matches('"').to_string())
+    }
+
+    /// Sign the built modules when the kernel checks signatures, with the
+    /// configured key or the one shipped in the headers' certs/
+    fn sign(kernel: &KConfig, modules: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        let headers = Path::new(&kernel.headers);
+        if Self::config(headers, "CONFIG_MODULE_SIG").as_deref() != Some("y") {
+            return Ok(());
+        }
+        let forced = Self::config(headers, "CONFIG_MODULE_SIG_FORCE").as_deref() == Some("y");
+
+        let local = |path: &Option<String>, default: &str| match path {
+            Some(path) => PathBuf::from(shellexpand::tilde(path).as_ref()),
+            None => headers.join(default),
+        };
+        let key = local(&kernel.signing_key, "certs/signing_key.pem");
+        let cert = local(&kernel.signing_cert, "certs/signing_key.x509");
+        if !key.exists() || !cert.exists() {
+            match forced {
+                true => log_error!(
+                    "No signing key for kernel {}, which only loads signed modules",
+                    kernel.version
+                ),
+                false => log_status!("No signing key for kernel {}, not signing", kernel.version),
             }
+            return Ok(());
+        }
+
+        let sign_file = headers.join("scripts/sign-file");
+        if !sign_file.exists() {
+            log_error!("{:?} is missing from the headers", sign_file);
+            return Err(BuildError.into());
+        }
+        let hash = Self::config(headers, "CONFIG_MODULE_SIG_HASH").unwrap_or("sha256".into());
+
+        for ko in modules {
+            // Incremental builds leave modules that were signed last time
+            if fs::read(ko)?.ends_with(MODULE_SIG_MAGIC) {
+                continue;
+            }
+            let res = Command::new(&sign_file)
+                .arg(&hash)
+                .arg(&key)
+                .arg(&cert)
+                .arg(ko)
+                .output()?;
+            if !res.status.success() {
+                print_output(&String::from_utf8_lossy(&res.stderr));
+                log_error!("Failed to sign {:?}", ko);
+                return Err(BuildError.into());
+            }
+        }
+        log_success!(
+            "Signed modules for kernel {} with {:?}",
+            kernel.version,
+            key
+        );
+        Ok(())
+    }
+
+    /// Compiler the kernel was built with, as recorded in its headers
+    fn kernel_compiler(headers: &Path) -> Option<String> {
+        // Since 5.8, the first line of `$(CC) --version`
+        if let Some(text) = Self::config(headers, "CONFIG_CC_VERSION_TEXT") {
+            return Some(text);
+        }
+
+        let compile = fs::read_to_string(headers.join("include/generated/compile.h")).ok()?;
+        compile
+            .lines()
+            .find_map(|l| l.strip_prefix("#define LINUX_COMPILER "))
+            .map(|text| text.trim_matches('"').to_string())
+    }
+
+    /// Compiler family and version, e.g. ("gcc", "12.2.0"), from
+    /// `--version` output or the kernel's record of it
+    fn compiler_id(text: &str) -> Option<(&'static str, String)> {
+        let family = match text.contains("clang") {
+            true => "clang",
+            false => "gcc",
+        };
+        Some((family, Self::version(text)?.to_string()))
+    }
+
+    /// First dotted version number in a tool's `--version` output
+    fn version(text: &str) -> Option<&str> {
+        text.split(|c: char| c.is_whitespace() || c == ',' || c == '(' || c == ')')
+            .find(|t| {
+                t.contains('.')
+                    && t.split('.')
+                        .all(|p| !p.is_empty() && p.parse::<u32>().is_ok())
+            })
+    }
+
+    /// First line of a tool's `--version` output, None if it can't be run
+    fn tool_version(tool: &str) -> Option<String> {
+        let res = Command::new(tool).arg("--version").output().ok()?;
+        if !res.status.success() {
+            return None;
+        }
+        let out = String::from_utf8_lossy(&res.stdout);
+        out.lines().next().map(str::to_string)
+    }
+
+    /// Check the headers were packaged with Rust support and the host
+    /// toolchain satisfies the kernel's scripts/min-tool-version.sh
+    fn check_rust(kernel: &KConfig) -> Result<(), Box<dyn Error>> {
+        let headers = Path::new(&kernel.headers);
+        if Self::config(headers, "CONFIG_RUST").as_deref() != Some("y") {
+            log_error!("Kernel {} was built without CONFIG_RUST", kernel.version);
+            return Err(BuildError.into());
+        }
+        for artifact in ["rust/libkernel.rmeta", "rust/libmacros.so"] {
+            if !headers.join(artifact).exists() {
+                log_error!(
+                    "{} is missing from the headers of {}, repackage them with Rust support",
+                    artifact,
+                    kernel.version
+                );
+                return Err(BuildError.into());
+            }
+        }
+
+        let parse = |v: &str| -> Vec<u32> { v.split('.').filter_map(|p| p.parse().ok()).collect() };
+        for tool in ["rustc", "bindgen"] {
+            let minimum = Command::new("sh")
+                .arg(headers.join("scripts/min-tool-version.sh"))
+                .arg(tool)
+                .env("ARCH", kernel.arch.kbuild())
+                .output()
+                .ok()
+                .map(|res| String::from_utf8_lossy(&res.stdout).trim().to_string())
+                .filter(|v| !v.is_empty());
+            let Some(found) = Self::tool_version(tool) else {
+                log_error!("{} is required to build Rust modules", tool);
+                return Err(BuildError.into());
+            };
+            let version = Self::version(&found).unwrap_or_default();
+            if let Some(minimum) = minimum {
+                if parse(version) < parse(&minimum) {
+                    log_error!(
+                        "{} {} is older than {} required by kernel {}",
+                        tool,
+                        version,
+                        minimum,
+                        kernel.version
+                    );
+                    return Err(BuildError.into());
+                }
+            }
+        }
+
+        // The kernel's .rmeta files only load into the exact same rustc
+        let found = Self::tool_version("rustc").unwrap_or_default();
+        if let Some(expected) = Self::config(headers, "CONFIG_RUSTC_VERSION_TEXT") {
+            if found != expected {
+                log_error!(
+                    "Kernel {} was built with {}, but the host has {}",
+                    kernel.version,
+                    expected,
+                    found
+                );
+                return Err(BuildError.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Warn when the compiler about to be used differs from the
+    /// kernel's, which Kbuild tends to reject or miscompile against
+    fn check_compiler(kernel: &KConfig, compiler: &str) {
+        let Some(expected) = Self::kernel_compiler(Path::new(&kernel.headers)) else {
+            return;
+        };
+        let Some(found) = Self::tool_version(compiler) else {
+            log_error!(
+                "Compiler {} not found, kernel {} was built with {}",
+                compiler,
+                kernel.version,
+                expected
+            );
+            return;
+        };
+        if Self::compiler_id(&found) != Self::compiler_id(&expected) {
+            log_error!(
+                "Compiler mismatch for kernel {}: building with {}, kernel was built with {}",
+                kernel.version,
+                found,
+                expected
+            );
+        }
+    }
+
+    /// Modules produced by a build, as listed in modules.order. Depending on
+    /// the kernel, entries are .o or .ko and may carry a kernel/ prefix.
+    fn modules(builddir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let order = match fs::read_to_string(builddir.join("modules.order")) {
+            Ok(order) => order,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut res = Vec::new();
+        for line in order.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            // Relative entries are below the build directory, never the
+            // current one, which may hold a stale .ko built by hand
+            let entry = Path::new(line).with_extension("ko");
+            let candidates = [
+                builddir.join(&entry),
+                builddir.join(entry.strip_prefix("kernel").unwrap_or(&entry)),
+                builddir.join(entry.file_name().ok_or(BadFilePath)?),
+            ];
+            match candidates.into_iter().find(|p| p.exists()) {
+                Some(ko) => res.push(ko),
+                None => log_error!("Module {} listed in modules.order is missing", line),
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(extra: &str) -> Module {
+        toml::from_str(&format!(
+            "name = \"hello\"\n\
+             test_files = []\n\
+             test_script = {{ local = \"test.sh\", remote = \"/root/test.sh\" }}\n\
+             {}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn kernel(extra: &str) -> KConfig {
+        toml::from_str(&format!(
+            "version = \"6.1.0\"\n\
+             url_base = \"http://localhost\"\n\
+             headers = \"headers.tar.gz\"\n\
+             kernel = \"bzImage\"\n\
+             {}\n\
+             [disk]\n\
+             url_base = \"http://localhost\"\n\
+             path = \"disk.img\"\n\
+             sshkey = \"id_rsa\"\n\
+             boot = \"/dev/sda\"\n",
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn kernel_toolchain_replaces_the_modules() {
+        let module = module("cc = \"gcc-12\"\nllvm = true");
+        assert_eq!(
+            ModuleBuilder::toolchain(&module, &kernel("")),
+            (Some("gcc-12"), true)
+        );
+        assert_eq!(
+            ModuleBuilder::toolchain(&module, &kernel("llvm = false")),
+            (None, false)
+        );
+        assert_eq!(
+            ModuleBuilder::toolchain(&module, &kernel("cc = \"gcc-13\"")),
+            (Some("gcc-13"), false)
+        );
+    }
+
+    #[test]
+    fn rust_modules_always_use_llvm() {
+        let module = module("kind = \"rust\"");
+        assert_eq!(
+            ModuleBuilder::toolchain(&module, &kernel("llvm = false")),
+            (None, true)
+        );
+        assert_eq!(ModuleBuilder::compiler(&module, &kernel("")), "clang");
+    }
+
+    #[test]
+    fn splits_output_by_kbuild_step() {
+        let log = "make: Entering directory '/cache/6.1.0/headers'\n\
+                   \x20 CC [M]  /build/6.1.0/hello.o\n\
+                   /build/6.1.0/hello.c:4:5: warning: unused variable 'x' [-Wunused-variable]\n\
+                   \x20   4 |     int x;\n\
+                   \x20 CC [M]  /build/6.1.0/util.o\n\
+                   \x20 MODPOST /build/6.1.0/Module.symvers\n\
+                   WARNING: modpost: missing MODULE_LICENSE() in /build/6.1.0/hello.o\n\
+                   \x20 LD [M]  /build/6.1.0/hello.ko\n";
+        let (units, unattributed) = split_units(log);
+        assert_eq!(
+            unattributed,
+            "make: Entering directory '/cache/6.1.0/headers'\n"
+        );
+        let names: Vec<&str> = units.iter().map(|(u, _)| u.as_str()).collect();
+        assert_eq!(
+            names,
+            ["/build/6.1.0/hello.o", "/build/6.1.0/util.o", "modpost"]
+        );
+        assert!(units[0].1.contains("unused variable"));
+        assert!(units[1].1.is_empty());
+        assert!(units[2].1.starts_with("WARNING: modpost"));
+    }
+
+    #[test]
+    fn recognizes_only_short_log_lines() {
+        assert_eq!(unit("  CC [M]  hello.o"), Some("hello.o".to_string()));
+        assert_eq!(
+            unit("  RUSTC [M] /build/rust_hello.o"),
+            Some("/build/rust_hello.o".to_string())
+        );
+        assert_eq!(unit("  LD [M]  /build/hello.ko"), None);
+        assert_eq!(unit("    4 |     int x;"), None);
+        assert_eq!(unit("WARNING: modpost: x"), None);
+    }
+
+    #[test]
+    fn splits_definitions_on_the_first_equals() {
+        assert_eq!(
+            ModuleBuilder::define("CFLAGS=-DX=1").unwrap(),
+            ("CFLAGS", "-DX=1")
+        );
+        assert!(ModuleBuilder::define("=1").is_err());
+        assert!(ModuleBuilder::define("NOVALUE").is_err());
+    }
+
+    #[test]
+    fn custom_makefiles_see_the_build_directory_as_pwd() {
+        let builddir = Path::new("/out/build/6.1.0");
+        let make =
+            ModuleBuilder::command(&module("custom_make = true"), &kernel(""), builddir).unwrap();
+        assert_eq!(make.get_current_dir(), Some(builddir));
+        assert!(make
+            .get_envs()
+            .any(|(name, value)| name == "PWD" && value == Some(builddir.as_os_str())));
+    }
+
+    #[test]
+    fn finds_modules_relative_to_the_build_directory() {
+        let root = std::env::temp_dir().join(format!("nixmodule-order-{}", std::process::id()));
+        let builddir = root.join("build");
+        fs::create_dir_all(builddir.join("sub")).unwrap();
+        fs::write(builddir.join("hello.ko"), "").unwrap();
+        fs::write(builddir.join("sub/helper.ko"), "").unwrap();
+        fs::write(
+            builddir.join("modules.order"),
+            "hello.o\nkernel/sub/helper.ko\n",
+        )
+        .unwrap();
+
+        // A module built by hand in the current directory is ignored
+        let stale = std::env::current_dir().unwrap().join("hello.ko");
+        let planted = !stale.exists() && fs::write(&stale, "").is_ok();
+        let modules = ModuleBuilder::modules(&builddir);
+        if planted {
+            fs::remove_file(&stale).unwrap();
         }
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            modules.unwrap(),
+            [builddir.join("hello.ko"), builddir.join("sub/helper.ko")]
+        );
     }
 }
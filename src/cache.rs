@@ -1,6 +1,7 @@
 use colored::*;
 use reqwest::blocking::Client;
 use reqwest::Url;
+use std::collections::HashSet;
 use std::error::Error;
 use std::ffi::OsStr;
 use std::fs;
@@ -8,6 +9,9 @@ use std::fs::File;
 use std::fs::Permissions;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use crate::errors::NixModuleError::*;
 use crate::KConfig;
@@ -21,6 +25,58 @@ pub struct Cache {
     dir: PathBuf,
 }
 
+/// Local paths of the artifacts needed to boot a kernel
+struct BootFiles {
+    kernel: String,
+    disk: String,
+    initrd: Option<String>,
+    sshkey: String,
+    vmlinux: Option<String>,
+}
+
+impl BootFiles {
+    /// Point the config at the local copies
+    fn apply(self, kernel: &mut KConfig) {
+        kernel.kernel = self.kernel;
+        kernel.disk.path = self.disk;
+        kernel.disk.initrd = self.initrd;
+        kernel.disk.sshkey = self.sshkey;
+        kernel.vmlinux = self.vmlinux;
+    }
+}
+
+/// Boot artifacts being downloaded in the background
+pub struct Prefetch {
+    rx: Receiver<(String, Result<BootFiles, String>)>,
+    skipped: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Prefetch {
+    /// Stop fetching a kernel that won't be booted, such as one whose
+    /// build failed. Artifacts not yet started are skipped.
+    pub fn skip(&self, kernel: &KConfig) {
+        if let Ok(mut skipped) = self.skipped.lock() {
+            skipped.insert(kernel.version.clone());
+        }
+    }
+
+    /// Wait for a kernel's boot artifacts, skipping those of
+    /// kernels that were never booted
+    pub fn wait(&self, kernel: &mut KConfig) -> Result<(), Box<dyn Error>> {
+        log_status!(
+            "Waiting for boot artifacts of Linux Kernel {}",
+            kernel.version
+        );
+        loop {
+            let (version, res) = self.rx.recv()?;
+            if version == kernel.version {
+                res?.apply(kernel);
+                return Ok(());
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 enum ArchiveType {
     TarGz,
@@ -41,81 +97,129 @@ impl Cache {
         }
     }
 
-    /// Retrieve a path from the cache.
-    ///
-    /// This initiates a download if the file isn't present
-    pub fn get(&self, kernel: &mut KConfig) -> Result<(), Box<dyn Error>> {
-        log_status!("Checking artifacts for Linux Kernel {}", kernel.version);
+    /// Download an artifact unless cached, returning its local path
+    fn fetch(&self, url: &str, cpath: PathBuf) -> Result<String, Box<dyn Error>> {
+        let dpath = self.download(url, &cpath)?;
+        self.check_local(&dpath, &cpath)?;
+        Ok(cpath.into_os_string().into_string().or(Err(BadFilePath))?)
+    }
 
-        // The cache folder for this KConfig
+    /// Retrieve only the headers from the cache, enough to build
+    /// the module without booting the kernel.
+    pub fn headers(&self, kernel: &mut KConfig) -> Result<(), Box<dyn Error>> {
+        log_status!("Checking headers for Linux Kernel {}", kernel.version);
         let cache_dir = self.dir.as_path().join("cache").join(&kernel.version);
-        let images_dir = self.dir.as_path().join("cache").join("images");
+        kernel.headers = self.fetch(
+            &format!("{}/{}", kernel.url_base, kernel.headers),
+            cache_dir.join("headers"),
+        )?;
+        Ok(())
+    }
 
-        // Get headers
-        let headers_url = format!("{}/{}", kernel.url_base, kernel.headers);
-        let headers_cpath = cache_dir.join("headers");
-        let headers_dpath = self.download(&headers_url, &headers_cpath)?;
-        self.check_local(&headers_dpath, &headers_cpath)?;
-
-        // Get bzImage
-        let kernel_url = format!("{}/{}", kernel.url_base, kernel.kernel);
-        let kernel_cpath = cache_dir.join(Path::new(&kernel.kernel).file_name().unwrap());
-        let kernel_dpath = self.download(&kernel_url, &kernel_cpath)?;
-        self.check_local(&kernel_dpath, &kernel_cpath)?;
-
-        // Get disk image
-        let disk_url = format!("{}/{}", kernel.disk.url_base, kernel.disk.path);
-        let disk_cpath = images_dir.join(Path::new(&kernel.disk.path).file_name().unwrap());
-        let disk_dpath = self.download(&disk_url, &disk_cpath)?;
-        self.check_local(&disk_dpath, &disk_cpath)?;
-
-        // Optional initrd
-        if let Some(ref path) = kernel.disk.initrd {
-            let initrd_url = format!("{}/{}", kernel.disk.url_base, path);
-            let initrd_cpath = images_dir.join(Path::new(&path).file_name().unwrap());
-            let initrd_dpath = self.download(&initrd_url, &initrd_cpath)?;
-            self.check_local(&initrd_dpath, &initrd_cpath)?;
-            kernel.disk.initrd = Some(
-                initrd_cpath
-                    .into_os_string()
-                    .into_string()
-                    .or(Err(BadFilePath))?,
-            );
-        }
+    /// Retrieve the optional vmlinux with debug info
+    pub fn vmlinux(&self, kernel: &mut KConfig) -> Result<(), Box<dyn Error>> {
+        kernel.vmlinux = self.fetch_vmlinux(kernel)?;
+        Ok(())
+    }
 
-        // Get ssh key
-        let key_url = format!("{}/{}", kernel.disk.url_base, kernel.disk.sshkey);
-        let key_cpath = images_dir.join(Path::new(&kernel.disk.sshkey).file_name().unwrap());
-        let key_dpath = self.download(&key_url, &key_cpath)?;
-        self.check_local(&key_dpath, &key_cpath)?;
-
-        // Update the local paths
-        kernel.headers = headers_cpath
-            .into_os_string()
-            .into_string()
-            .or(Err(BadFilePath))?;
-        kernel.kernel = kernel_cpath
-            .into_os_string()
-            .into_string()
-            .or(Err(BadFilePath))?;
-        kernel.disk.path = disk_cpath
-            .into_os_string()
-            .into_string()
-            .or(Err(BadFilePath))?;
-        kernel.disk.sshkey = key_cpath
-            .into_os_string()
-            .into_string()
-            .or(Err(BadFilePath))?;
+    fn fetch_vmlinux(&self, kernel: &KConfig) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(ref path) = kernel.vmlinux else {
+            return Ok(None);
+        };
+        let cache_dir = self.dir.as_path().join("cache").join(&kernel.version);
+        let vmlinux = self.fetch(
+            &format!("{}/{}", kernel.url_base, path),
+            cache_dir.join(Path::new(path).file_name().ok_or(BadFilePath)?),
+        )?;
+        Ok(Some(vmlinux))
+    }
 
+    /// Download everything needed to boot a kernel, without touching the
+    /// config so it can run in the background. Gives up between artifacts
+    /// once `skipped` returns true.
+    fn fetch_boot(
+        &self,
+        kernel: &KConfig,
+        skipped: &dyn Fn() -> bool,
+    ) -> Result<BootFiles, Box<dyn Error>> {
+        let cache_dir = self.dir.as_path().join("cache").join(&kernel.version);
+        let images_dir = self.dir.as_path().join("cache").join("images");
+        let image = |base: &str, path: &str, dir: &Path| -> Result<String, Box<dyn Error>> {
+            if skipped() {
+                return Err(format!("Skipped boot artifacts of {}", kernel.version).into());
+            }
+            self.fetch(
+                &format!("{}/{}", base, path),
+                dir.join(Path::new(path).file_name().ok_or(BadFilePath)?),
+            )
+        };
+
+        let disk = &kernel.disk;
+        Ok(BootFiles {
+            kernel: image(&kernel.url_base, &kernel.kernel, &cache_dir)?,
+            disk: image(&disk.url_base, &disk.path, &images_dir)?,
+            initrd: match disk.initrd {
+                Some(ref path) => Some(image(&disk.url_base, path, &images_dir)?),
+                None => None,
+            },
+            sshkey: image(&disk.url_base, &disk.sshkey, &images_dir)?,
+            vmlinux: match kernel.vmlinux {
+                Some(ref path) => Some(image(&kernel.url_base, path, &cache_dir)?),
+                None => None,
+            },
+        })
+    }
+
+    /// Retrieve the kernel image, disk, initrd, ssh key and vmlinux
+    pub fn boot(&self, kernel: &mut KConfig) -> Result<(), Box<dyn Error>> {
+        log_status!(
+            "Checking boot artifacts for Linux Kernel {}",
+            kernel.version
+        );
+        self.fetch_boot(kernel, &|| false)?.apply(kernel);
         Ok(())
     }
 
+    /// Retrieve every artifact of a kernel from the cache.
+    ///
+    /// This initiates a download if the file isn't present
+    pub fn get(&self, kernel: &mut KConfig) -> Result<(), Box<dyn Error>> {
+        self.headers(kernel)?;
+        self.boot(kernel)
+    }
+
+    /// Download the boot artifacts of each kernel, in order, on a
+    /// background thread while the main thread builds
+    pub fn prefetch(&self, kernels: Vec<KConfig>) -> Prefetch {
+        let (tx, rx) = mpsc::channel();
+        let skipped = Arc::new(Mutex::new(HashSet::new()));
+        let cache = Cache {
+            dir: self.dir.clone(),
+        };
+        let shared = skipped.clone();
+        thread::spawn(move || {
+            for kernel in kernels {
+                let is_skipped = || shared.lock().is_ok_and(|s| s.contains(&kernel.version));
+                let res = cache
+                    .fetch_boot(&kernel, &is_skipped)
+                    .map_err(|e| e.to_string());
+                if tx.send((kernel.version, res)).is_err() {
+                    break;
+                }
+            }
+        });
+        Prefetch { rx, skipped }
+    }
+
     /// Checks the cache path, or unpacks an existing download
     fn check_local(&self, dpath: &PathBuf, cpath: &PathBuf) -> Result<(), Box<dyn Error>> {
         // This response is already cached
         if cpath.as_path().exists() {
             return Ok(());
         }
+        if let Some(parent) = cpath.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
         // If the file is an archive, unpack it first
         match dpath.extension() {
@@ -137,7 +241,7 @@ impl Cache {
         let url = Url::parse(uri)?;
         let fname = url
             .path_segments()
-            .and_then(|segments| segments.last())
+            .and_then(|mut segments| segments.next_back())
             .and_then(|name| if name.is_empty() { None } else { Some(name) })
             .unwrap_or("tmp.bin");
 
@@ -160,9 +264,13 @@ impl Cache {
             return Err(format!("{} not found", uri).into());
         }
 
-        // Create the outfile
-        let mut outfile = File::create(&fname)?;
+        // Download next to the outfile and move it in place once complete,
+        // so an interrupted download is never mistaken for a cached one
+        let mut partial = fname.clone().into_os_string();
+        partial.push(".part");
+        let mut outfile = File::create(&partial)?;
         response.copy_to(&mut outfile)?;
+        fs::rename(&partial, &fname)?;
         Ok(fname)
     }
 
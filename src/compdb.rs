@@ -0,0 +1,156 @@
+use crate::errors::NixModuleError::*;
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A compile_commands.json entry
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    directory: String,
+    file: String,
+    command: String,
+}
+
+/// Compile command recorded in a Kbuild `.<obj>.cmd` file. Newer
+/// kernels prefix it with `savedcmd_`, older ones with `cmd_`.
+fn command(cmd: &str) -> Option<String> {
+    let line = cmd
+        .lines()
+        .find(|l| l.starts_with("savedcmd_") || l.starts_with("cmd_"))?;
+    let (_, command) = line.split_once(" := ")?;
+
+    // Older kernels chain objtool and friends after the compiler
+    let command = command.split(" ; ").next()?;
+    Some(command.trim().replace("\\#", "#"))
+}
+
+/// Every `.*.o.cmd` file below `dir`
+fn cmd_files(dir: &Path, res: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if path.is_dir() {
+            cmd_files(&path, res)?;
+        } else if name.starts_with('.') && name.ends_with(".o.cmd") {
+            res.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Build a compile database from the `.cmd` files Kbuild left in
+/// `builddir`. Kbuild runs from the headers, and paths into the build
+/// directory are mapped back onto the module sources in `srcdir`.
+pub fn generate(
+    builddir: &Path,
+    srcdir: &Path,
+    headers: &Path,
+) -> Result<Vec<Entry>, Box<dyn Error>> {
+    let builddir = fs::canonicalize(builddir)?;
+    let builddir = builddir.to_str().ok_or(BadFilePath)?;
+    let srcdir = srcdir.to_str().ok_or(BadFilePath)?;
+    let directory = fs::canonicalize(headers)?
+        .into_os_string()
+        .into_string()
+        .or(Err(BadFilePath))?;
+
+    let mut files = Vec::new();
+    cmd_files(Path::new(builddir), &mut files)?;
+    files.sort();
+
+    let mut res = Vec::new();
+    for path in files {
+        let Some(command) = command(&fs::read_to_string(&path)?) else {
+            continue;
+        };
+
+        // The source is the last operand, generated .mod.c files have none to index
+        let Some(file) = command
+            .split_whitespace()
+            .rev()
+            .find(|t| t.ends_with(".c") || t.ends_with(".S"))
+        else {
+            continue;
+        };
+        if file.ends_with(".mod.c") {
+            continue;
+        }
+        let file = match Path::new(file).is_absolute() {
+            true => file.to_string(),
+            false => format!("{}/{}", directory, file),
+        };
+
+        res.push(Entry {
+            directory: directory.clone(),
+            file: file.replace(builddir, srcdir),
+            command: command.replace(builddir, srcdir),
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_savedcmd() {
+        let cmd = "savedcmd_/build/6.1.0/hello.o := gcc -Wp,-MMD,/build/6.1.0/.hello.o.d -nostdinc -I./include -DKBUILD_MODNAME=\\\"hello\\\" -c -o /build/6.1.0/hello.o /build/6.1.0/hello.c\n\
+                   \n\
+                   source_/build/6.1.0/hello.o := /build/6.1.0/hello.c\n";
+        assert_eq!(
+            command(cmd).unwrap(),
+            "gcc -Wp,-MMD,/build/6.1.0/.hello.o.d -nostdinc -I./include -DKBUILD_MODNAME=\\\"hello\\\" -c -o /build/6.1.0/hello.o /build/6.1.0/hello.c"
+        );
+    }
+
+    #[test]
+    fn reads_older_cmd_and_drops_chained_tools() {
+        let cmd = "cmd_/build/4.19.0/hello.o := gcc -Wp,-MD,/build/4.19.0/.hello.o.d -DKBUILD_STR(s)=\\#s -c -o /build/4.19.0/hello.o /build/4.19.0/hello.c ; ./tools/objtool/objtool orc generate --module /build/4.19.0/hello.o\n";
+        assert_eq!(
+            command(cmd).unwrap(),
+            "gcc -Wp,-MD,/build/4.19.0/.hello.o.d -DKBUILD_STR(s)=#s -c -o /build/4.19.0/hello.o /build/4.19.0/hello.c"
+        );
+        assert_eq!(command("deps_/build/hello.o := \\\n"), None);
+    }
+
+    #[test]
+    fn maps_build_paths_to_sources() {
+        let root = std::env::temp_dir().join(format!("nixmodule-compdb-{}", std::process::id()));
+        let builddir = root.join("build");
+        let headers = root.join("headers");
+        fs::create_dir_all(builddir.join("sub")).unwrap();
+        fs::create_dir_all(&headers).unwrap();
+        let build = fs::canonicalize(&builddir).unwrap();
+        let build = build.display();
+        fs::write(
+            builddir.join("sub/.util.o.cmd"),
+            format!(
+                "savedcmd_{0}/sub/util.o := gcc -c -o {0}/sub/util.o {0}/sub/util.c\n",
+                build
+            ),
+        )
+        .unwrap();
+        fs::write(
+            builddir.join(".hello.mod.o.cmd"),
+            format!(
+                "savedcmd_{0}/hello.mod.o := gcc -c -o {0}/hello.mod.o {0}/hello.mod.c\n",
+                build
+            ),
+        )
+        .unwrap();
+
+        let entries = generate(&builddir, Path::new("/src/hello"), &headers).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file, "/src/hello/sub/util.c");
+        assert_eq!(
+            entries[0].command,
+            "gcc -c -o /src/hello/sub/util.o /src/hello/sub/util.c"
+        );
+    }
+}
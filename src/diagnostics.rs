@@ -0,0 +1,167 @@
+use std::fmt;
+
+/// Severity of a compiler diagnostic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A single GCC/Clang or modpost diagnostic from the build output
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub severity: Severity,
+    pub flag: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Parse `file:line[:col]: severity: message [-Wflag]`, or the
+    /// `WARNING: modpost: message` lines Kbuild emits itself
+    fn parse(line: &str) -> Option<Self> {
+        if let Some(message) = line.strip_prefix("WARNING: modpost: ") {
+            return Some(Self {
+                file: None,
+                line: None,
+                severity: Severity::Warning,
+                flag: Some("modpost".to_string()),
+                message: message.to_string(),
+            });
+        }
+
+        let (location, severity, rest) = [
+            (": warning: ", Severity::Warning),
+            (": error: ", Severity::Error),
+            (": fatal error: ", Severity::Error),
+        ]
+        .into_iter()
+        .find_map(|(marker, severity)| {
+            line.split_once(marker)
+                .map(|(location, rest)| (location, severity, rest))
+        })?;
+
+        // The column is optional, the line isn't
+        let mut parts = location.splitn(3, ':');
+        let file = parts.next()?.trim();
+        let lineno = parts.next()?.parse().ok()?;
+
+        // Trailing [-Wflag] or [-Werror=flag]
+        let (message, flag) = match rest.rsplit_once(" [") {
+            Some((message, flag)) if flag.starts_with("-W") && flag.ends_with(']') => (
+                message,
+                Some(flag.trim_end_matches(']').replace("-Werror=", "-W")),
+            ),
+            _ => (rest, None),
+        };
+
+        Some(Self {
+            file: Some(file.to_string()),
+            line: Some(lineno),
+            severity,
+            flag,
+            message: message.to_string(),
+        })
+    }
+
+    /// Every diagnostic in the build output, skipping repeats
+    /// from objects that are rebuilt more than once
+    pub fn parse_all(output: &str) -> Vec<Self> {
+        let mut res: Vec<Self> = Vec::new();
+        for diag in output.lines().filter_map(Self::parse) {
+            if !res.contains(&diag) {
+                res.push(diag);
+            }
+        }
+        res
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (Some(file), Some(line)) = (&self.file, self.line) {
+            write!(f, "{}:{}: ", file, line)?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(ref flag) = self.flag {
+            write!(f, " [{}]", flag)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gcc_diagnostics() {
+        let diag = Diagnostic::parse(
+            "/build/6.1.0/hello.c:12:9: warning: unused variable 'x' [-Wunused-variable]",
+        )
+        .unwrap();
+        assert_eq!(diag.file.as_deref(), Some("/build/6.1.0/hello.c"));
+        assert_eq!(diag.line, Some(12));
+        assert_eq!(diag.severity, Severity::Warning);
+        assert_eq!(diag.flag.as_deref(), Some("-Wunused-variable"));
+        assert_eq!(diag.message, "unused variable 'x'");
+
+        let diag = Diagnostic::parse(
+            "/build/6.1.0/hello.c:20:1: error: no return statement in function returning non-void [-Werror=return-type]",
+        )
+        .unwrap();
+        assert_eq!(diag.severity, Severity::Error);
+        assert_eq!(diag.flag.as_deref(), Some("-Wreturn-type"));
+
+        let diag = Diagnostic::parse(
+            "/build/6.1.0/hello.c:1:10: fatal error: linux/nope.h: No such file or directory",
+        )
+        .unwrap();
+        assert_eq!(diag.severity, Severity::Error);
+        assert_eq!(diag.message, "linux/nope.h: No such file or directory");
+        assert_eq!(diag.flag, None);
+    }
+
+    #[test]
+    fn parses_clang_diagnostics() {
+        let diag = Diagnostic::parse(
+            "/build/6.1.0/hello.c:5:6: warning: no previous prototype for function 'helper' [-Wmissing-prototypes]",
+        )
+        .unwrap();
+        assert_eq!(diag.line, Some(5));
+        assert_eq!(diag.flag.as_deref(), Some("-Wmissing-prototypes"));
+        assert_eq!(
+            diag.to_string(),
+            "/build/6.1.0/hello.c:5: no previous prototype for function 'helper' [-Wmissing-prototypes]"
+        );
+    }
+
+    #[test]
+    fn parses_modpost_warnings() {
+        let diag =
+            Diagnostic::parse("WARNING: modpost: missing MODULE_LICENSE() in /build/6.1.0/hello.o")
+                .unwrap();
+        assert_eq!(diag.file, None);
+        assert_eq!(diag.flag.as_deref(), Some("modpost"));
+        assert_eq!(
+            diag.to_string(),
+            "missing MODULE_LICENSE() in /build/6.1.0/hello.o [modpost]"
+        );
+    }
+
+    #[test]
+    fn skips_context_and_repeats() {
+        let log = "  CC [M]  /build/6.1.0/hello.o\n\
+                   /build/6.1.0/hello.c: In function 'hello_init':\n\
+                   /build/6.1.0/hello.c:12:9: warning: unused variable 'x' [-Wunused-variable]\n\
+                   \x20  12 |     int x;\n\
+                   \x20     |         ^\n\
+                   /build/6.1.0/hello.c:3:10: note: declared here\n\
+                   cc1: all warnings being treated as errors\n\
+                   /build/6.1.0/hello.c:12:9: warning: unused variable 'x' [-Wunused-variable]\n";
+        let diags = Diagnostic::parse_all(log);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].line, Some(12));
+    }
+}
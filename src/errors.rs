@@ -5,7 +5,7 @@ use core::fmt::Result;
 use std::error::Error;
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum NixModuleError {
     Success = 0,
@@ -16,6 +16,8 @@ pub enum NixModuleError {
     InsmodError,
     TestError,
     TimeoutError,
+    KvmError,
+    SignatureError,
 }
 
 impl Display for NixModuleError {
@@ -0,0 +1,244 @@
+use crate::errors::NixModuleError::*;
+use crate::loader::Load;
+use crate::qemu::Qemu;
+use colored::*;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Ask the OS for a free local port
+pub fn free_port() -> Result<u16, Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Where the module's symbols are loaded from
+enum Symbols {
+    /// Section addresses of an already loaded module
+    Loaded(Vec<(String, String)>),
+    /// Resolved from `struct module` when do_init_module is hit for
+    /// `name`, after gdb runs the loader command in the background
+    OnLoad {
+        version: (u32, u32),
+        name: String,
+        loader: String,
+    },
+}
+
+/// Generated gdb script for a debug session
+pub struct GdbScript {
+    port: u16,
+    vmlinux: Option<String>,
+    module: PathBuf,
+    symbols: Symbols,
+}
+
+/// Major and minor of a kernel version string
+fn kernel_version(version: &str) -> (u32, u32) {
+    let mut parts = version.split('.').map(|v| v.parse().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// Expressions for the text and init text base of `struct module *mod`,
+/// the layout changed in 4.5 and again in 6.4
+fn module_layout(version: (u32, u32)) -> (&'static str, &'static str) {
+    match version {
+        v if v >= (6, 4) => ("mod->mem[MOD_TEXT].base", "mod->mem[MOD_INIT_TEXT].base"),
+        v if v >= (4, 5) => ("mod->core_layout.base", "mod->init_layout.base"),
+        _ => ("mod->module_core", "mod->module_init"),
+    }
+}
+
+/// `name address` pairs listed from /sys/module/<name>/sections
+fn sections(out: &str) -> Vec<(String, String)> {
+    out.lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(s, addr)| (s.to_string(), addr.trim().to_string()))
+        .collect()
+}
+
+impl GdbScript {
+    /// Prepare a script that breaks at the module's init function. gdb
+    /// runs `loader` (usually insmod over ssh) once its breakpoints are set.
+    pub fn on_load(
+        handle: &Qemu,
+        version: &str,
+        vmlinux: &Option<String>,
+        module: &Load,
+        loader: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        if vmlinux.is_none() {
+            log_error!("Breaking on module load requires a vmlinux for {}", version);
+            return Err(BadFilePath.into());
+        }
+        Ok(Self {
+            port: handle.gdbport().ok_or(QemuError)?,
+            vmlinux: vmlinux.clone(),
+            module: fs::canonicalize(&module.ko)?,
+            symbols: Symbols::OnLoad {
+                version: kernel_version(version),
+                name: module.name.clone(),
+                loader: handle.ssh_cmdline(loader),
+            },
+        })
+    }
+
+    /// Prepare a script for the module loaded in the running VM, reading
+    /// its section addresses from /sys/module over ssh.
+    pub fn loaded(
+        handle: &Qemu,
+        vmlinux: &Option<String>,
+        module: &Path,
+        name: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        let port = handle.gdbport().ok_or(QemuError)?;
+
+        // Not every section name starts with a dot, e.g. __ksymtab or
+        // __param, and -f leaves out . and ..
+        let out = handle.runcmd_output(&format!(
+            "cd /sys/module/{}/sections && for s in .* *; do [ -f \"$s\" ] && echo \"$s\" $(cat \"$s\"); done; true",
+            name
+        ))?;
+        let sections = sections(&out);
+        if !sections.iter().any(|(s, _)| s == ".text") {
+            log_error!("Module {} isn't loaded, it has no .text section", name);
+            return Err(InsmodError.into());
+        }
+
+        Ok(Self {
+            port,
+            vmlinux: vmlinux.clone(),
+            module: fs::canonicalize(module)?,
+            symbols: Symbols::Loaded(sections),
+        })
+    }
+
+    /// Render the gdb commands
+    fn render(&self) -> Result<String, Box<dyn Error>> {
+        let mut script = String::new();
+        match self.vmlinux {
+            Some(ref vmlinux) => writeln!(script, "file {}", vmlinux)?,
+            None => writeln!(script, "# No vmlinux configured for this kernel")?,
+        }
+        writeln!(script, "target remote localhost:{}", self.port)?;
+
+        match self.symbols {
+            Symbols::Loaded(ref sections) => {
+                // .text is positional, the rest are passed with -s
+                let text = sections
+                    .iter()
+                    .find(|(s, _)| s == ".text")
+                    .ok_or(InsmodError)?;
+                write!(
+                    script,
+                    "add-symbol-file {} {}",
+                    self.module.display(),
+                    text.1
+                )?;
+                for (section, addr) in sections.iter().filter(|(s, _)| s != ".text") {
+                    write!(script, " -s {} {}", section, addr)?;
+                }
+                writeln!(script)?;
+            }
+            Symbols::OnLoad {
+                version,
+                ref name,
+                ref loader,
+            } => {
+                let (text, init) = module_layout(version);
+                writeln!(
+                    script,
+                    "tbreak do_init_module if $_streq(mod->name, \"{}\")",
+                    name
+                )?;
+                writeln!(script, "commands")?;
+                writeln!(script, "  silent")?;
+                writeln!(
+                    script,
+                    "  eval \"add-symbol-file {} 0x%lx -s .init.text 0x%lx\", (unsigned long){}, (unsigned long){}",
+                    self.module.display(),
+                    text,
+                    init
+                )?;
+                writeln!(script, "  if mod->init != 0")?;
+                writeln!(script, "    tbreak *mod->init")?;
+                writeln!(script, "  end")?;
+                writeln!(script, "  continue")?;
+                writeln!(script, "end")?;
+                writeln!(script, "shell {} &", loader)?;
+                writeln!(script, "continue")?;
+            }
+        }
+        Ok(script)
+    }
+
+    /// Write the script to disk
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.render()?)?;
+        log_status!("Wrote gdb script to {:?}", path);
+        log_status!("Use 'gdb -x {}' to attach to the guest", path.display());
+        Ok(())
+    }
+
+    /// Run gdb with the script in the foreground,
+    /// does not return until gdb exits
+    pub fn launch(path: &Path) -> Result<(), Box<dyn Error>> {
+        Command::new("gdb").arg("-q").arg("-x").arg(path).status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_kernel_versions() {
+        assert_eq!(kernel_version("5.17.2"), (5, 17));
+        assert_eq!(kernel_version("6.4"), (6, 4));
+        assert_eq!(kernel_version("4"), (4, 0));
+    }
+
+    #[test]
+    fn picks_module_layout_by_version() {
+        assert_eq!(module_layout((6, 4)).0, "mod->mem[MOD_TEXT].base");
+        assert_eq!(module_layout((6, 1)).0, "mod->core_layout.base");
+        assert_eq!(module_layout((4, 5)).1, "mod->init_layout.base");
+        assert_eq!(
+            module_layout((4, 4)),
+            ("mod->module_core", "mod->module_init")
+        );
+    }
+
+    #[test]
+    fn keeps_sections_without_a_leading_dot() {
+        let out = ".text 0xffffffffc0002000\n\
+                   .data 0xffffffffc0004000\n\
+                   __ksymtab 0xffffffffc0005000\n\
+                   __param 0xffffffffc0005040\n";
+        let sections = sections(out);
+        assert_eq!(sections.len(), 4);
+        assert_eq!(
+            sections[2],
+            ("__ksymtab".to_string(), "0xffffffffc0005000".to_string())
+        );
+    }
+
+    #[test]
+    fn renders_every_section() {
+        let script = GdbScript {
+            port: 1234,
+            vmlinux: None,
+            module: PathBuf::from("/tmp/example.ko"),
+            symbols: Symbols::Loaded(sections(".data 0x2000\n.text 0x1000\n__bug_table 0x3000\n")),
+        };
+        let script = script.render().unwrap();
+        assert!(script.contains("target remote localhost:1234"));
+        assert!(script.contains(
+            "add-symbol-file /tmp/example.ko 0x1000 -s .data 0x2000 -s __bug_table 0x3000"
+        ));
+    }
+}
@@ -0,0 +1,337 @@
+use crate::builder::ModuleBuilder;
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::utils::is_build_output;
+use crate::{KConfig, Module};
+use colored::*;
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// Static analysis tools run after a successful build
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Linter {
+    Sparse,
+    Checkpatch,
+    Smatch,
+    Coccinelle,
+}
+
+impl fmt::Display for Linter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Linter::Sparse => "sparse",
+            Linter::Checkpatch => "checkpatch",
+            Linter::Smatch => "smatch",
+            Linter::Coccinelle => "coccinelle",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Output of a linter and what was found in it
+pub struct Report {
+    pub log: String,
+    pub findings: Vec<Diagnostic>,
+}
+
+/// Whether a tool can be spawned from PATH
+fn installed(tool: &str) -> bool {
+    Command::new(tool)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// Combined stdout and stderr
+fn output(res: Output) -> String {
+    String::from_utf8_lossy(&res.stdout).into_owned() + &String::from_utf8_lossy(&res.stderr)
+}
+
+/// Diagnostic at a `file:line[:col]` location
+fn finding(
+    location: &str,
+    severity: Severity,
+    flag: Option<String>,
+    message: &str,
+) -> Option<Diagnostic> {
+    let mut parts = location.trim().splitn(3, ':');
+    let file = parts.next()?;
+    let line = parts.next()?.parse().ok()?;
+    Some(Diagnostic {
+        file: Some(file.to_string()),
+        line: Some(line),
+        severity,
+        flag,
+        message: message.trim().to_string(),
+    })
+}
+
+/// `file:line func() warn: message`
+fn parse_smatch(line: &str) -> Option<Diagnostic> {
+    let (head, severity, message) = [
+        (" warn: ", Severity::Warning),
+        (" error: ", Severity::Error),
+    ]
+    .into_iter()
+    .find_map(|(marker, severity)| {
+        line.split_once(marker)
+            .map(|(head, message)| (head, severity, message))
+    })?;
+    finding(head.split_whitespace().next()?, severity, None, message)
+}
+
+/// `file:line: SEVERITY:TYPE: message` from `checkpatch.pl --terse --show-types`
+fn parse_checkpatch(line: &str) -> Option<Diagnostic> {
+    let (location, rest) = line.split_once(": ")?;
+    let (severity, rest) = rest.split_once(':')?;
+    let severity = match severity {
+        "ERROR" => Severity::Error,
+        "WARNING" | "CHECK" => Severity::Warning,
+        _ => return None,
+    };
+    let (kind, message) = rest.split_once(':')?;
+    finding(location, severity, Some(kind.to_string()), message)
+}
+
+/// `file:line:cols: WARNING: message` from `make coccicheck MODE=report`
+fn parse_coccinelle(line: &str) -> Option<Diagnostic> {
+    let (location, severity, message) = [
+        (": WARNING", Severity::Warning),
+        (": ERROR", Severity::Error),
+    ]
+    .into_iter()
+    .find_map(|(marker, severity)| {
+        line.split_once(marker)
+            .map(|(location, message)| (location, severity, message))
+    })?;
+    let message = message.trim_start_matches(':');
+    finding(location, severity, Some("coccinelle".to_string()), message)
+}
+
+/// C sources and headers of the module, relative to its root
+fn sources(root: &Path, dir: &Path, res: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_build_output(&path) {
+            continue;
+        }
+        if path.is_dir() {
+            sources(root, &path, res)?;
+        } else if matches!(path.extension().and_then(|e| e.to_str()), Some("c" | "h")) {
+            res.push(path.strip_prefix(root)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+impl Linter {
+    /// Run against a kernel on the already built `builddir`, returning the
+    /// output and the findings. Ok(None) when the tool isn't available.
+    pub fn run(
+        &self,
+        module: &Module,
+        kernel: &KConfig,
+        builddir: &Path,
+    ) -> Result<Option<Report>, Box<dyn Error>> {
+        let builddir = fs::canonicalize(builddir)?;
+        match self {
+            // Rerun the checker over every source, objects are up to date
+            Linter::Sparse | Linter::Smatch => {
+                let tool = match self {
+                    Linter::Sparse => "sparse",
+                    _ => "smatch",
+                };
+                if !installed(tool) {
+                    return Ok(None);
+                }
+                let mut make = ModuleBuilder::command(module, kernel, &builddir)?;
+                make.arg("C=2");
+                if *self == Linter::Smatch {
+                    make.arg("CHECK=smatch -p=kernel");
+                }
+                let log = output(make.output()?);
+                let findings = match self {
+                    Linter::Sparse => Diagnostic::parse_all(&log),
+                    _ => log.lines().filter_map(parse_smatch).collect(),
+                };
+                Ok(Some(Report { log, findings }))
+            }
+            // Prefer the kernel's own copy, its rules match the version
+            Linter::Checkpatch => {
+                let bundled = Path::new(&kernel.headers).join("scripts/checkpatch.pl");
+                let script = match bundled.exists() {
+                    true => bundled,
+                    false if installed("checkpatch.pl") => PathBuf::from("checkpatch.pl"),
+                    false => return Ok(None),
+                };
+                let root = ModuleBuilder::srcdir(module)?;
+                let mut files = Vec::new();
+                sources(&root, &root, &mut files)?;
+                if files.is_empty() {
+                    return Ok(Some(Report {
+                        log: String::new(),
+                        findings: Vec::new(),
+                    }));
+                }
+                let res = Command::new(script)
+                    .current_dir(&root)
+                    .args(["--no-tree", "--terse", "--show-types", "--file"])
+                    .args(&files)
+                    .output()?;
+                let log = output(res);
+                let findings = log.lines().filter_map(parse_checkpatch).collect();
+                Ok(Some(Report { log, findings }))
+            }
+            // Needs the semantic patches shipped in the kernel tree
+            Linter::Coccinelle => {
+                let scripts = Path::new(&kernel.headers).join("scripts/coccinelle");
+                if !installed("spatch") || !scripts.exists() {
+                    return Ok(None);
+                }
+                let res = ModuleBuilder::make(module, kernel)?
+                    .arg("-C")
+                    .arg(&kernel.headers)
+                    .arg(format!("M={}", builddir.display()))
+                    .args(["coccicheck", "MODE=report"])
+                    .output()?;
+                let log = output(res);
+                let findings = log.lines().filter_map(parse_coccinelle).collect();
+                Ok(Some(Report { log, findings }))
+            }
+        }
+    }
+}
+
+/// Run every linter, printing findings and reporting missing tools.
+/// Returns the combined log and the number of findings.
+pub fn lint(
+    linters: &[Linter],
+    module: &Module,
+    kernel: &KConfig,
+    builddir: &Path,
+) -> Result<(String, usize), Box<dyn Error>> {
+    let mut log = String::new();
+    let mut count = 0;
+    for linter in linters {
+        log_status!("Running {} for {}", linter, kernel.version);
+        let Some(Report { log: out, findings }) = linter.run(module, kernel, builddir)? else {
+            log_error!("{} is not available on this host, skipping", linter);
+            continue;
+        };
+        log += &format!("==> {}\n{}\n", linter, out);
+        if findings.is_empty() {
+            log_success!("{} found nothing for {}", linter, kernel.version);
+            continue;
+        }
+        log_error!(
+            "{} findings from {} for {}:",
+            findings.len(),
+            linter,
+            kernel.version
+        );
+        for finding in findings.iter() {
+            println!("    {}", finding);
+        }
+        count += findings.len();
+    }
+    Ok((log, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_checkpatch() {
+        let diag = parse_checkpatch(
+            "hello.c:12: WARNING:LINE_SPACING: Missing a blank line after declarations",
+        )
+        .unwrap();
+        assert_eq!(diag.file.as_deref(), Some("hello.c"));
+        assert_eq!(diag.line, Some(12));
+        assert_eq!(diag.severity, Severity::Warning);
+        assert_eq!(diag.flag.as_deref(), Some("LINE_SPACING"));
+        assert_eq!(diag.message, "Missing a blank line after declarations");
+
+        let diag = parse_checkpatch(
+            "hello.c:3: ERROR:SPACING: space required before the open parenthesis '('",
+        )
+        .unwrap();
+        assert_eq!(diag.severity, Severity::Error);
+        let diag = parse_checkpatch(
+            "hello.c:40: CHECK:PARENTHESIS_ALIGNMENT: Alignment should match open parenthesis",
+        )
+        .unwrap();
+        assert_eq!(diag.severity, Severity::Warning);
+
+        assert_eq!(
+            parse_checkpatch("total: 1 errors, 1 warnings, 20 lines checked"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_smatch() {
+        let diag = parse_smatch(
+            "/build/6.1.0/hello.c:15 hello_init() warn: inconsistent returns '&lock'.",
+        )
+        .unwrap();
+        assert_eq!(diag.file.as_deref(), Some("/build/6.1.0/hello.c"));
+        assert_eq!(diag.line, Some(15));
+        assert_eq!(diag.severity, Severity::Warning);
+        assert_eq!(diag.message, "inconsistent returns '&lock'.");
+
+        let diag = parse_smatch(
+            "/build/6.1.0/hello.c:20 hello_exit() error: we previously assumed 'p' could be null (see line 18)",
+        )
+        .unwrap();
+        assert_eq!(diag.severity, Severity::Error);
+        assert_eq!(diag.line, Some(20));
+
+        assert_eq!(parse_smatch("  CHECK   /build/6.1.0/hello.c"), None);
+    }
+
+    #[test]
+    fn parses_coccinelle() {
+        let diag = parse_coccinelle(
+            "/build/6.1.0/hello.c:25:8-14: WARNING: kzalloc should be used for buf, instead of kmalloc/memset",
+        )
+        .unwrap();
+        assert_eq!(diag.file.as_deref(), Some("/build/6.1.0/hello.c"));
+        assert_eq!(diag.line, Some(25));
+        assert_eq!(diag.flag.as_deref(), Some("coccinelle"));
+        assert_eq!(
+            diag.message,
+            "kzalloc should be used for buf, instead of kmalloc/memset"
+        );
+
+        let diag = parse_coccinelle(
+            "/build/6.1.0/hello.c:31:1-7: ERROR: missing put_device; call of_find_device_by_node on line 28",
+        )
+        .unwrap();
+        assert_eq!(diag.severity, Severity::Error);
+
+        assert_eq!(
+            parse_coccinelle(
+                "Please check for false positives in the output before submitting a patch."
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_sparse_through_the_compiler_format() {
+        let log = "  CHECK   /build/6.1.0/hello.c\n\
+                   /build/6.1.0/hello.c:30:17: warning: incorrect type in argument 1 (different address spaces)\n\
+                   /build/6.1.0/hello.c:30:17:    expected void const volatile [noderef] __user *ptr\n";
+        let diags = Diagnostic::parse_all(log);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].line, Some(30));
+    }
+}
@@ -0,0 +1,294 @@
+use crate::builder::ModuleBuilder;
+use crate::errors::NixModuleError::{self, *};
+use crate::qemu::Qemu;
+use crate::Module;
+use colored::*;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Kernel messages for modules rejected over their signature
+const SIGNATURE_REJECTED: &[&str] = &[
+    "Loading of unsigned module is rejected",
+    "Loading of module with unavailable key is rejected",
+    "Loading of module with unsupported crypto is rejected",
+];
+
+/// Logged to the kernel ring buffer before each load, so only
+/// messages from that attempt are searched for a rejection
+const LOAD_MARKER: &str = "nixmodule: loading";
+
+/// Module tree used with modprobe, relative to /lib/modules/$(uname -r)
+const MODPROBE_ROOT: &str = "/tmp/nixmodule";
+
+/// A built module and how to load it
+pub struct Load {
+    pub name: String,
+    pub ko: PathBuf,
+    args: String,
+}
+
+/// Loads and unloads the built modules in dependency order
+pub struct Loader {
+    modules: Vec<Load>,
+    primary: usize,
+    modprobe: bool,
+}
+
+impl Loader {
+    /// Work out the load order of the built modules. Without a
+    /// `[[module.load]]` list, only the module named in the config is loaded.
+    pub fn plan(module: &Module, built: &[PathBuf]) -> Result<Self, Box<dyn Error>> {
+        let find = |name: &str| {
+            built
+                .iter()
+                .find(|ko| ModuleBuilder::modname(ko) == Some(ModuleBuilder::normalize(name)))
+        };
+
+        if module.load.is_empty() {
+            let ko = find(&module.name).or(built.first()).ok_or(BuildError)?;
+            return Ok(Self {
+                modules: vec![Load {
+                    name: ModuleBuilder::modname(ko).ok_or(BadFilePath)?,
+                    ko: ko.clone(),
+                    args: module.insmod_args.clone(),
+                }],
+                primary: 0,
+                modprobe: module.modprobe,
+            });
+        }
+
+        // Stable topological sort, dependencies outside the
+        // list are expected to be provided by the kernel
+        let names: Vec<String> = module
+            .load
+            .iter()
+            .map(|l| ModuleBuilder::normalize(&l.name))
+            .collect();
+        let mut pending: Vec<_> = module.load.iter().collect();
+        let mut modules: Vec<Load> = Vec::new();
+        while !pending.is_empty() {
+            let ready = pending.iter().position(|l| {
+                l.depends
+                    .iter()
+                    .map(|d| ModuleBuilder::normalize(d))
+                    .all(|d| !names.contains(&d) || modules.iter().any(|m| m.name == d))
+            });
+            let Some(idx) = ready else {
+                log_error!("Module dependencies form a cycle");
+                return Err(BuildError.into());
+            };
+            let entry = pending.remove(idx);
+            let Some(ko) = find(&entry.name) else {
+                log_error!("Module {} was not built", entry.name);
+                return Err(BuildError.into());
+            };
+            modules.push(Load {
+                name: ModuleBuilder::normalize(&entry.name),
+                ko: ko.clone(),
+                args: entry.insmod_args.clone(),
+            });
+        }
+
+        let primary = modules
+            .iter()
+            .position(|m| m.name == ModuleBuilder::normalize(&module.name))
+            .unwrap_or(modules.len() - 1);
+        Ok(Self {
+            modules,
+            primary,
+            modprobe: module.modprobe,
+        })
+    }
+
+    /// The module named in the config, which debugging focuses on
+    pub fn primary(&self) -> &Load {
+        &self.modules[self.primary]
+    }
+
+    /// Where a module is uploaded to
+    fn remote(ko: &Path) -> Result<String, Box<dyn Error>> {
+        Ok(format!(
+            "/tmp/{}",
+            ko.file_name().and_then(|n| n.to_str()).ok_or(BadFilePath)?
+        ))
+    }
+
+    /// Upload all modules. For modprobe they are installed into a
+    /// private module tree and indexed with depmod.
+    pub fn upload(&self, handle: &Qemu) -> Result<(), Box<dyn Error>> {
+        for load in self.modules.iter() {
+            let remote = Self::remote(&load.ko)?;
+            handle.transfer(load.ko.to_str().ok_or(BadFilePath)?, &remote)?;
+            log_status!("Uploaded {}", remote);
+        }
+        if !self.modprobe {
+            return Ok(());
+        }
+
+        let extra = format!("{}/lib/modules/$(uname -r)/extra", MODPROBE_ROOT);
+        let mut cmd = format!("mkdir -p {}", extra);
+        for load in self.modules.iter() {
+            cmd += &format!(" && cp {} {}/", Self::remote(&load.ko)?, extra);
+        }
+        cmd += &format!(" && depmod -b {} $(uname -r)", MODPROBE_ROOT);
+        handle.runcmd(&cmd)?;
+        Ok(())
+    }
+
+    /// Shell command loading a single module
+    fn command(&self, load: &Load) -> Result<String, Box<dyn Error>> {
+        Ok(match self.modprobe {
+            true => format!("modprobe -d {} {} {}", MODPROBE_ROOT, load.name, load.args),
+            false => format!("insmod {} {}", Self::remote(&load.ko)?, load.args),
+        })
+    }
+
+    /// Single shell command loading every module in order
+    pub fn commands(&self) -> Result<String, Box<dyn Error>> {
+        let cmds = self
+            .modules
+            .iter()
+            .map(|l| self.command(l))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(cmds.join(" && "))
+    }
+
+    /// Load every module in order, failing with SignatureError
+    /// when the kernel rejected a module's signature
+    pub fn load(&self, handle: &Qemu) -> Result<(), Box<dyn Error>> {
+        for load in self.modules.iter() {
+            handle.runcmd(&format!("echo '{} {}' > /dev/kmsg", LOAD_MARKER, load.name))?;
+            if handle.runcmd(&self.command(load)?).is_err() {
+                return Err(Self::failure(handle, load).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell signature rejections apart from other load failures
+    fn failure(handle: &Qemu, load: &Load) -> NixModuleError {
+        let dmesg = handle.runcmd_output("dmesg").unwrap_or_default();
+        match Self::rejection(&dmesg, &load.name) {
+            Some(reason) => {
+                log_error!("Kernel rejected the signature of {}: {}", load.name, reason);
+                SignatureError
+            }
+            None => InsmodError,
+        }
+    }
+
+    /// Signature rejection logged since the module's load marker
+    fn rejection(dmesg: &str, name: &str) -> Option<&'static str> {
+        let marker = format!("{} {}\n", LOAD_MARKER, name);
+        let (_, since) = dmesg.rsplit_once(&marker)?;
+        SIGNATURE_REJECTED
+            .iter()
+            .find(|m| since.contains(*m))
+            .copied()
+    }
+
+    /// Unload any loaded modules in reverse order
+    pub fn unload(&self, handle: &Qemu) -> Result<(), Box<dyn Error>> {
+        let cmds: Vec<String> = self
+            .modules
+            .iter()
+            .rev()
+            .map(|l| format!("if [ -d /sys/module/{0} ]; then rmmod {0}; fi", l.name))
+            .collect();
+        handle.runcmd(&cmds.join(" && "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(load: &str) -> Module {
+        let config = format!(
+            "name = \"main-mod\"\n\
+             insmod_args = \"debug=1\"\n\
+             test_files = []\n\
+             test_script = {{ local = \"test.sh\", remote = \"/root/test.sh\" }}\n\
+             {}",
+            load
+        );
+        toml::from_str(&config).unwrap()
+    }
+
+    fn built(names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|n| PathBuf::from(format!("/build/{}.ko", n)))
+            .collect()
+    }
+
+    fn order(loader: &Loader) -> Vec<&str> {
+        loader.modules.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    #[test]
+    fn loads_the_named_module_by_default() {
+        let loader = Loader::plan(&module(""), &built(&["helper", "main-mod"])).unwrap();
+        assert_eq!(order(&loader), ["main_mod"]);
+        assert_eq!(loader.primary().ko, PathBuf::from("/build/main-mod.ko"));
+        assert_eq!(loader.primary().args, "debug=1");
+    }
+
+    #[test]
+    fn orders_dependencies_first() {
+        let load = r#"
+            [[load]]
+            name = "main-mod"
+            depends = ["core", "helper"]
+
+            [[load]]
+            name = "helper"
+            depends = ["core", "crc32c"]
+
+            [[load]]
+            name = "core"
+            insmod_args = "size=4"
+        "#;
+        let loader = Loader::plan(&module(load), &built(&["main-mod", "helper", "core"])).unwrap();
+        assert_eq!(order(&loader), ["core", "helper", "main_mod"]);
+        assert_eq!(loader.primary().name, "main_mod");
+        assert_eq!(loader.modules[0].args, "size=4");
+    }
+
+    #[test]
+    fn rejects_dependency_cycles() {
+        let load = r#"
+            [[load]]
+            name = "a"
+            depends = ["b"]
+
+            [[load]]
+            name = "b"
+            depends = ["a"]
+        "#;
+        assert!(Loader::plan(&module(load), &built(&["a", "b"])).is_err());
+    }
+
+    #[test]
+    fn rejects_modules_that_were_not_built() {
+        let load = r#"
+            [[load]]
+            name = "missing"
+        "#;
+        assert!(Loader::plan(&module(load), &built(&["main-mod"])).is_err());
+    }
+
+    #[test]
+    fn only_reports_rejections_since_the_load() {
+        let dmesg = "[    2.1] nixmodule: loading hello\n\
+                     [    2.2] Loading of unsigned module is rejected\n\
+                     [    9.0] nixmodule: loading hello_ext\n\
+                     [    9.4] hello_ext: Unknown symbol foo (err -2)\n";
+        assert_eq!(Loader::rejection(dmesg, "hello_ext"), None);
+        assert_eq!(
+            Loader::rejection(dmesg, "hello"),
+            Some("Loading of unsigned module is rejected")
+        );
+        assert_eq!(Loader::rejection("", "hello"), None);
+    }
+}
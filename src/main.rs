@@ -1,11 +1,13 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::*;
 use prettytable::Table;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs::read;
+use std::fs::{self, read};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[macro_use]
 mod utils;
@@ -17,13 +19,33 @@ mod errors;
 use errors::NixModuleError::{self, *};
 
 mod qemu;
-use qemu::Qemu;
+use qemu::{Accel, Qemu, QemuOpts};
 
 mod ssh;
-use ssh::SshVersion;
 
 mod builder;
-use builder::ModuleBuilder;
+use builder::{Build, ModuleBuilder, ModuleKind};
+
+mod diagnostics;
+
+mod lint;
+use lint::Linter;
+
+mod compdb;
+
+mod arch;
+use arch::Arch;
+
+mod gdb;
+use gdb::GdbScript;
+
+mod symbolize;
+
+mod loader;
+use loader::Loader;
+
+mod watch;
+use watch::Watcher;
 
 #[macro_use]
 extern crate prettytable;
@@ -35,7 +57,8 @@ struct Opt {
     #[clap(
         short = 'c',
         long = "config",
-        default_value = "./nixmodule-config.toml"
+        default_value = "./nixmodule-config.toml",
+        global = true
     )]
     config: PathBuf,
 
@@ -43,13 +66,87 @@ struct Opt {
     /// or any kernel that starts with this value
     /// (i.e 5 will run every 5.X.X vs 5.1 which will
     /// only run 5.1*)
-    #[clap(short = 'k', long = "kernel")]
+    #[clap(short = 'k', long = "kernel", global = true)]
     kernel: Option<String>,
 
     /// Enter a shell on the box, also starts qemu with
     /// gdb. Performs the build + setup stages first.
     #[clap(short = 'd', long = "debug")]
     debug: bool,
+
+    /// Launch gdb with the generated script before
+    /// entering the shell, implies --debug
+    #[clap(long = "gdb")]
+    gdb: bool,
+
+    /// Stop in gdb at the module's init function, gdb performs
+    /// the insmod once its breakpoints are set. Implies --gdb
+    #[clap(long = "break-on-load")]
+    break_on_load: bool,
+
+    /// Directory to store console logs and crash dumps in,
+    /// one subdirectory per run and kernel
+    #[clap(
+        short = 'o',
+        long = "output",
+        default_value = "./nixmodule-runs",
+        global = true
+    )]
+    output: PathBuf,
+
+    /// Keep the VMs booted and rerun the build, insmod
+    /// and tests whenever the module sources change
+    #[clap(short = 'w', long = "watch")]
+    watch: bool,
+
+    /// Open a shell on the VM when a stage fails,
+    /// before it is torn down
+    #[clap(long = "shell-on-failure")]
+    shell_on_failure: bool,
+
+    /// Fail the build stage when the compiler emits warnings
+    #[clap(long = "werror")]
+    werror: bool,
+
+    /// Only build against the selected kernels, in parallel,
+    /// without downloading images or booting any VM
+    #[clap(short = 'b', long = "build-only")]
+    build_only: bool,
+
+    /// Static analysis to run after each build, in
+    /// addition to the module's `lint` list
+    #[clap(long = "lint", value_enum)]
+    lint: Vec<Linter>,
+
+    /// Talk to the VMs through the host's ssh and scp
+    /// binaries instead of the built-in client
+    #[clap(long = "subprocess-ssh")]
+    subprocess_ssh: bool,
+
+    #[clap(subcommand)]
+    command: Option<Cmd>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Boot the selected kernels and drop into a shell,
+    /// without building or loading the module
+    Shell,
+
+    /// Boot the selected kernels and keep them running
+    /// in the background
+    Up,
+
+    /// Rebuild the module, reload it and rerun the tests
+    /// on the VMs started with `up`
+    Reload,
+
+    /// Stop the VMs started with `up`
+    Down,
+
+    /// Build against the selected kernel's headers and write
+    /// compile_commands.json next to the module sources
+    Compdb,
 }
 
 #[derive(Debug, Deserialize)]
@@ -62,10 +159,79 @@ struct Config {
 #[derive(Debug, Deserialize)]
 pub struct Module {
     name: String,
+
+    // Language the module is written in, Rust modules
+    // are built with LLVM=1
+    #[serde(default)]
+    kind: ModuleKind,
+
+    // Modules to load in dependency order, defaults to
+    // just `name` with `insmod_args`
+    #[serde(default)]
+    load: Vec<LoadModule>,
+
+    // Load through modprobe with a generated modules.dep
+    // instead of insmod
+    #[serde(default)]
+    modprobe: bool,
+
+    // Directory containing the module sources, defaults
+    // to the current directory
+    source: Option<String>,
+
+    // The Makefile builds the module itself when passed
+    // KERNEL= and TARGET=, instead of invoking Kbuild directly
+    #[serde(default)]
+    custom_make: bool,
+
+    // Compiler to build with, overridden per kernel
+    cc: Option<String>,
+
+    // Build with the LLVM toolchain (LLVM=1)
+    llvm: Option<bool>,
+
+    // Extra arguments passed to make for every kernel
+    #[serde(default)]
+    make_args: Vec<String>,
+
     test_script: UploadFile,
+    #[serde(default)]
     insmod_args: String,
+
+    // NAME=VALUE environment variables for make, build_defines
+    // is the older name for the same thing
     build_defines: Option<Vec<String>>,
+    #[serde(default)]
+    env: Vec<String>,
+
+    // NAME=VALUE variables passed on the make command line,
+    // overriding assignments in the Makefile
+    #[serde(default)]
+    make_vars: Vec<String>,
+
+    // Fail the build stage when the compiler emits warnings
+    #[serde(default)]
+    werror: bool,
+
+    // Static analysis to run after each build
+    #[serde(default)]
+    lint: Vec<Linter>,
+
     test_files: Vec<UploadFile>,
+
+    // Additional independent test scripts, each run from a
+    // snapshot of the freshly booted VM
+    #[serde(default)]
+    test_cases: Vec<UploadFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoadModule {
+    name: String,
+    #[serde(default)]
+    insmod_args: String,
+    #[serde(default)]
+    depends: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -74,7 +240,7 @@ pub struct UploadFile {
     remote: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct DiskImage {
     url_base: String,
     path: String,
@@ -83,20 +249,55 @@ pub struct DiskImage {
     boot: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct KConfig {
     version: String,
     url_base: String,
     headers: String,
     kernel: String,
+    vmlinux: Option<String>,
     disk: DiskImage,
-    runner: String,
+    runner: Option<String>,
     runner_extra_args: Option<Vec<String>>,
 
+    // Guest architecture, defaults to x86_64
+    #[serde(default)]
+    arch: Arch,
+
+    // Toolchain prefix used to cross-compile the module
+    cross_compile: Option<String>,
+
+    // Compiler matching the one the kernel was built with
+    cc: Option<String>,
+
+    // The kernel was built with the LLVM toolchain (LLVM=1),
+    // this and `cc` override both of the module's settings
+    llvm: Option<bool>,
+
+    // Key and certificate to sign modules with, defaulting to
+    // certs/signing_key.pem and .x509 shipped with the headers
+    signing_key: Option<String>,
+    signing_cert: Option<String>,
+
+    // Extra arguments passed to make for this kernel
+    #[serde(default)]
+    make_args: Vec<String>,
+
+    // NAME=VALUE environment and make variables for this
+    // kernel, added after the module's
+    #[serde(default)]
+    env: Vec<String>,
+    #[serde(default)]
+    make_vars: Vec<String>,
+
     // Allow users to disable kvm
     #[serde(default = "enable_kvm")]
     kvm: bool,
 
+    // Fall back to TCG when kvm is unavailable on the host
+    #[serde(default = "enable_tcg_fallback")]
+    tcg_fallback: bool,
+
     // Allow users to increase timeout
     timeout: Option<u64>,
 }
@@ -106,62 +307,507 @@ fn enable_kvm() -> bool {
     true
 }
 
-/// Run through the test
-fn test(
+/// Prefer a slow run over no run at all
+fn enable_tcg_fallback() -> bool {
+    true
+}
+
+/// Name of the snapshot taken before running test cases
+const SNAPSHOT: &str = "nixmodule-ready";
+
+/// Counts gathered while testing a kernel, None for stages not reached
+#[derive(Default)]
+struct Stats {
+    warnings: Option<usize>,
+    findings: Option<usize>,
+}
+
+/// Build the module and run the linters, recording how many
+/// warnings and lint findings were raised. Logs go to `kdir`.
+fn build(
     module: &Module,
     kernel: &KConfig,
-    handle: &Qemu,
-    debug: bool,
-) -> Result<(), Box<dyn Error>> {
+    opt: &Opt,
+    kdir: &Path,
+    stats: &mut Stats,
+) -> Result<(Build, Loader), Box<dyn Error>> {
     log_status!("Building module for {}", kernel.version);
 
     // Compile the module against the headers
-    let build = ModuleBuilder::build(&module.name, &module.build_defines, kernel)?;
+    let builddir = opt.output.join("build").join(&kernel.version);
+    let built = ModuleBuilder::build(module, kernel, &builddir, kdir, &[&opt.output])?;
+    stats.warnings = Some(built.warnings.len());
+    if !built.warnings.is_empty() {
+        log_error!(
+            "{} warnings for kernel {}:",
+            built.warnings.len(),
+            kernel.version
+        );
+        for warning in built.warnings.iter() {
+            println!("    {}", warning);
+        }
+        if opt.werror || module.werror {
+            return Err(BuildError.into());
+        }
+    }
+    let loader = Loader::plan(module, &built.modules)?;
     log_success!("Build success for kernel {:?}", kernel.version);
 
-    // Upload the module
-    let uploaded = format!(
-        "/tmp/{:?}",
-        Path::new(&build).file_name().ok_or(BadFilePath)?
-    );
-    handle.transfer(&build, &uploaded).or(Err(InsmodError))?;
-    log_status!("Uploaded {}", uploaded);
+    // Findings are reported, they don't fail the run
+    let linters: Vec<Linter> = module.lint.iter().chain(opt.lint.iter()).copied().collect();
+    if !linters.is_empty() {
+        let (log, findings) = lint::lint(&linters, module, kernel, &builddir)?;
+        fs::write(kdir.join("lint.log"), log)?;
+        stats.findings = Some(findings);
+    }
+    Ok((built, loader))
+}
 
-    // Perform insmod
-    if !debug {
-        handle
-            .runcmd(&format!("insmod {} {}", uploaded, module.insmod_args))
-            .or(Err(InsmodError))?;
-        log_success!("Insmod successful for {}!", kernel.version);
+/// Load the built modules on a booted VM and run the tests,
+/// collecting what a failure left behind
+fn test(
+    module: &Module,
+    kernel: &KConfig,
+    handle: &Qemu,
+    opt: &Opt,
+    built: &Build,
+    loader: &Loader,
+) -> Result<(), Box<dyn Error>> {
+    let res = run(module, kernel, handle, opt, loader);
+    if res.is_err() {
+        // Decode any stack traces the failure left behind
+        symbolize::report(handle, kernel, &built.modules)
+            .unwrap_or_else(|e| log_error!("Failed to decode stack traces: {}", e));
+
+        crashed(kernel, handle);
     }
+    res
+}
+
+/// Surface kernel panics reported through pvpanic, dumping the guest
+fn crashed(kernel: &KConfig, handle: &Qemu) {
+    if handle.panicked() {
+        log_error!("Kernel {} panicked", kernel.version);
+        match handle.collect_crash(kernel) {
+            Ok(vmcore) => log_status!("Crash dump saved to {:?}", vmcore),
+            Err(e) => log_error!("Failed to dump guest memory: {}", e),
+        }
+    }
+}
+
+/// Upload, load and test the built modules
+fn run(
+    module: &Module,
+    kernel: &KConfig,
+    handle: &Qemu,
+    opt: &Opt,
+    loader: &Loader,
+) -> Result<(), Box<dyn Error>> {
+    // Unload the previous build from a VM that's being reused
+    if opt.watch || matches!(opt.command, Some(Cmd::Reload)) {
+        loader.unload(handle).or(Err(InsmodError))?;
+    }
+
+    // Upload the modules
+    loader.upload(handle).or(Err(InsmodError))?;
 
     // Upload all test files
-    handle
-        .transfer(&module.test_script.local, &module.test_script.remote)
-        .or(Err(TestError))?;
-    for upload in &module.test_files {
+    let cases: Vec<&UploadFile> = std::iter::once(&module.test_script)
+        .chain(module.test_cases.iter())
+        .collect();
+    for upload in cases.iter().copied().chain(module.test_files.iter()) {
         handle
             .transfer(&upload.local, &upload.remote)
             .or(Err(TestError))?;
     }
 
-    // Run the test script or enter an interactive session
-    if !debug {
-        handle
-            .runcmd(&module.test_script.remote)
-            .or(Err(TestError))?;
-        log_success!("Test successful for {}!", kernel.version);
+    // Leave gdb to perform the insmod when breaking on load
+    let primary = loader.primary();
+    let gdbinit = handle.rundir().join("gdbinit");
+    if opt.break_on_load {
+        GdbScript::on_load(
+            handle,
+            &kernel.version,
+            &kernel.vmlinux,
+            primary,
+            &loader.commands()?,
+        )?
+        .write(&gdbinit)?;
+        return Ok(());
+    }
+
+    // Load the module and leave the box as is for an interactive session
+    if opt.debug {
+        loader.load(handle)?;
+        GdbScript::loaded(handle, &kernel.vmlinux, &primary.ko, &primary.name)?.write(&gdbinit)?;
+        return Ok(());
+    }
+
+    // Only pay for a snapshot when there's more than one case
+    let snapshot = cases.len() > 1;
+    if snapshot {
+        handle.snapshot(SNAPSHOT).or(Err(TestError))?;
+    }
+
+    let mut failed = 0;
+    for (i, case) in cases.iter().enumerate() {
+        if snapshot && i > 0 {
+            // The restore forgets a panic, dump the guest the last case left
+            crashed(kernel, handle);
+            handle.restore(SNAPSHOT).or(Err(TestError))?;
+        }
+
+        // Perform insmod
+        loader.load(handle)?;
+        log_success!("Insmod successful for {}!", kernel.version);
+
+        // Run the test script
+        match handle.runcmd_streamed(&case.remote) {
+            Ok(_) => log_success!("Test {} successful for {}!", case.remote, kernel.version),
+            Err(_) => {
+                log_error!("Test {} failed for {}", case.remote, kernel.version);
+                failed += 1;
+            }
+        }
     }
 
+    if failed > 0 {
+        return Err(TestError.into());
+    }
+    Ok(())
+}
+
+/// Stage a result failed at, or Success
+fn status_of(result: &Result<(), Box<dyn Error>>) -> NixModuleError {
+    match result {
+        Ok(_) => Success,
+        Err(x) => x
+            .downcast_ref::<NixModuleError>()
+            .copied()
+            .unwrap_or(QemuError),
+    }
+}
+
+/// Add a kernel's results row, `accel` is None when it was never booted
+fn add_row(
+    table: &mut Table,
+    kernel: &KConfig,
+    accel: Option<Accel>,
+    stats: &Stats,
+    status: NixModuleError,
+) -> Result<(), Box<dyn Error>> {
+    let mut row = row![
+        kernel.version,
+        Fb->"N/A",
+        "N/A".blue(),
+        "N/A".blue(),
+        "N/A".blue(),
+        "N/A".blue(),
+        "N/A".blue()
+    ];
+    if let Some(accel) = accel {
+        row.set_cell(cell!(accel), 6)?;
+    }
+    for (count, col) in [(stats.warnings, 2), (stats.findings, 3)] {
+        match count {
+            Some(0) => row.set_cell(cell!(Fg->"0"), col)?,
+            Some(n) => row.set_cell(cell!(Fy->n), col)?,
+            None => {}
+        }
+    }
+    match status {
+        BuildError => {
+            row.set_cell(cell!(Fr->"Failed"), 1)?;
+        }
+        InsmodError => {
+            row.set_cell(cell!(Fg->"Ok"), 1)?;
+            row.set_cell(cell!(Fr->"Failed"), 4)?;
+        }
+        SignatureError => {
+            row.set_cell(cell!(Fg->"Ok"), 1)?;
+            row.set_cell(cell!(Fr->"Signature"), 4)?;
+        }
+        TestError => {
+            row.set_cell(cell!(Fg->"Ok"), 1)?;
+            row.set_cell(cell!(Fg->"Ok"), 4)?;
+            row.set_cell(cell!(Fr->"Failed"), 5)?;
+        }
+        Success => {
+            row.set_cell(cell!(Fg->"Ok"), 1)?;
+            row.set_cell(cell!(Fg->"Ok"), 4)?;
+            row.set_cell(cell!(Fg->"Ok"), 5)?;
+        }
+        _ => {}
+    }
+    table.add_row(row);
+    Ok(())
+}
+
+/// Build and test against an already booted VM and add its
+/// results row, returns the stage that failed or Success
+fn check(
+    module: &Module,
+    kernel: &KConfig,
+    handle: &Qemu,
+    opt: &Opt,
+    table: &mut Table,
+) -> Result<NixModuleError, Box<dyn Error>> {
+    let mut stats = Stats::default();
+    let result = build(module, kernel, opt, handle.rundir(), &mut stats)
+        .and_then(|(built, loader)| test(module, kernel, handle, opt, &built, &loader));
+    let status = status_of(&result);
+    add_row(table, kernel, Some(handle.accel()), &stats, status)?;
+    Ok(status)
+}
+
+/// Build against every kernel in parallel without booting
+/// them, returns BuildError if any build failed
+fn build_only<'a>(
+    module: &Module,
+    kernels: impl Iterator<Item = &'a mut KConfig>,
+    cache: &Cache,
+    rundir: &Path,
+    opt: &Opt,
+) -> Result<NixModuleError, Box<dyn Error>> {
+    let mut fetched = Vec::new();
+    for kernel in kernels {
+        cache.headers(kernel)?;
+        fetched.push(&*kernel);
+    }
+
+    // Each kernel has its own build directory, so builds don't collide
+    let results: Vec<(NixModuleError, Stats)> = std::thread::scope(|scope| {
+        let builds: Vec<_> = fetched
+            .iter()
+            .map(|kernel| {
+                scope.spawn(move || {
+                    let mut stats = Stats::default();
+                    let kdir = rundir.join(&kernel.version);
+                    let result = build(module, kernel, opt, &kdir, &mut stats).map(|_| ());
+                    (status_of(&result), stats)
+                })
+            })
+            .collect();
+        builds
+            .into_iter()
+            .map(|build| build.join().unwrap_or((BuildError, Stats::default())))
+            .collect()
+    });
+
+    let mut failed = Success;
+    let mut table = Table::new();
+    table.add_row(row![Fy->"Version", Fy->"Build", Fy->"Warnings", Fy->"Lint"]);
+    for (kernel, (status, stats)) in fetched.iter().zip(results) {
+        let mut row = row![kernel.version, Fg->"Ok", "N/A".blue(), "N/A".blue()];
+        if status != Success {
+            failed = BuildError;
+            row.set_cell(cell!(Fr->"Failed"), 1)?;
+        }
+        for (count, col) in [(stats.warnings, 2), (stats.findings, 3)] {
+            match count {
+                Some(0) => row.set_cell(cell!(Fg->"0"), col)?,
+                Some(n) => row.set_cell(cell!(Fy->n), col)?,
+                None => {}
+            }
+        }
+        table.add_row(row);
+    }
+    table.printstd();
+    Ok(failed)
+}
+
+/// Build against a kernel's headers and write its compile database
+/// to the module's source directory
+fn compdb(
+    module: &Module,
+    kernel: &mut KConfig,
+    cache: &Cache,
+    rundir: &Path,
+    opt: &Opt,
+) -> Result<(), Box<dyn Error>> {
+    cache.headers(kernel)?;
+    let builddir = opt.output.join("build").join(&kernel.version);
+    let kdir = rundir.join(&kernel.version);
+    ModuleBuilder::build(module, kernel, &builddir, &kdir, &[&opt.output])?;
+
+    let srcdir = ModuleBuilder::srcdir(module)?;
+    let entries = compdb::generate(&builddir, &srcdir, Path::new(&kernel.headers))?;
+    if entries.is_empty() {
+        log_error!("No compile commands found in {:?}", builddir);
+        return Err(BuildError.into());
+    }
+    let path = srcdir.join("compile_commands.json");
+    fs::write(&path, serde_json::to_string_pretty(&entries)? + "\n")?;
+    log_success!(
+        "Wrote {} entries for kernel {} to {:?}",
+        entries.len(),
+        kernel.version,
+        path
+    );
+    Ok(())
+}
+
+/// Boot the kernels once, then rebuild, reload and retest
+/// whenever the module's sources change. Guests left panicked
+/// or unreachable by an iteration are rebooted.
+fn watch<'a>(
+    module: &Module,
+    kernels: impl Iterator<Item = &'a mut KConfig>,
+    cache: &Cache,
+    rundir: &Path,
+    opt: &Opt,
+    opts: QemuOpts,
+) -> Result<(), Box<dyn Error>> {
+    let boot = |kernel: &KConfig| match Qemu::start(kernel, &rundir.join(&kernel.version), opts) {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            log_error!("Failed to boot kernel {}: {}", kernel.version, e);
+            None
+        }
+    };
+
+    let mut vms = Vec::new();
+    for kernel in kernels {
+        cache.get(kernel)?;
+        let handle = boot(kernel);
+        vms.push((kernel, handle));
+    }
+
+    let root = ModuleBuilder::srcdir(module)?;
+    let mut watcher = Watcher::new(&root, &[&opt.output]);
+    let mut previous: HashMap<String, NixModuleError> = HashMap::new();
+    loop {
+        let mut table = results_table();
+        let mut current = HashMap::new();
+        for (kernel, slot) in vms.iter_mut() {
+            // Retry guests that failed to come back last time
+            if slot.is_none() {
+                *slot = boot(kernel);
+            }
+            let Some(handle) = slot else {
+                add_row(&mut table, kernel, None, &Stats::default(), QemuError)?;
+                current.insert(kernel.version.clone(), QemuError);
+                continue;
+            };
+
+            let status = check(module, kernel, handle, opt, &mut table).unwrap_or_else(|e| {
+                log_error!("Kernel {} failed: {}", kernel.version, e);
+                QemuError
+            });
+            current.insert(kernel.version.clone(), status);
+
+            // Don't let one bad module fail every later iteration
+            if handle.panicked() || !handle.reachable() {
+                log_error!(
+                    "Kernel {} is no longer usable, rebooting it",
+                    kernel.version
+                );
+                if let Some(handle) = slot.take() {
+                    handle
+                        .stop()
+                        .unwrap_or_else(|e| log_error!("Failed to stop qemu: {}", e));
+                }
+                *slot = boot(kernel);
+            }
+        }
+        table.printstd();
+
+        // Compact summary of what changed since the last iteration
+        for (kernel, _) in vms.iter() {
+            match (previous.get(&kernel.version), current.get(&kernel.version)) {
+                (Some(before), Some(after)) if before != after => {
+                    log_status!("{}: {} -> {}", kernel.version, before, after);
+                }
+                _ => {}
+            }
+        }
+        previous = current;
+
+        for path in watcher.wait() {
+            log_status!("Changed {:?}", path);
+        }
+    }
+}
+
+/// Results table with its header row
+fn results_table() -> Table {
+    let mut table = Table::new();
+    table.add_row(row![
+        Fy->"Version",
+        Fy->"Build",
+        Fy->"Warnings",
+        Fy->"Lint",
+        Fy->"Insmod",
+        Fy->"Tests",
+        Fy->"Accel"
+    ]);
+    table
+}
+
+/// Boot a kernel and open a shell on it
+fn shell(
+    kernel: &mut KConfig,
+    cache: &Cache,
+    rundir: &Path,
+    opts: QemuOpts,
+) -> Result<(), Box<dyn Error>> {
+    cache.get(kernel)?;
+    let handle = Qemu::start(kernel, &rundir.join(&kernel.version), opts)?;
+    if let Some(port) = handle.gdbport() {
+        log_status!("gdb server listening on localhost:{}", port);
+    }
+    handle.interact().unwrap_or_else(|e| println!("{:?}", e));
+    handle.stop()
+}
+
+/// Where the state of a VM kept alive with `up` is recorded
+fn state_path(output: &Path, kernel: &KConfig) -> PathBuf {
+    output.join("vms").join(format!("{}.json", kernel.version))
+}
+
+/// Boot a kernel in the background and record its state
+fn up(
+    kernel: &mut KConfig,
+    cache: &Cache,
+    rundir: &Path,
+    output: &Path,
+    opts: QemuOpts,
+) -> Result<(), Box<dyn Error>> {
+    let state = state_path(output, kernel);
+    if Qemu::attach(&state).is_ok() {
+        log_status!("Kernel {} is already up", kernel.version);
+        return Ok(());
+    }
+    cache.get(kernel)?;
+    let handle = Qemu::start(kernel, &rundir.join(&kernel.version), opts)?;
+    handle.save(&state)?;
+    log_success!("Kernel {} is up", kernel.version);
+    Ok(())
+}
+
+/// Stop a kernel started with `up`
+fn down(kernel: &KConfig, output: &Path) -> Result<(), Box<dyn Error>> {
+    let state = state_path(output, kernel);
+    if !state.exists() {
+        return Ok(());
+    }
+    match Qemu::attach(&state) {
+        Ok(handle) => handle.stop()?,
+        Err(_) => log_status!("Kernel {} was no longer running", kernel.version),
+    }
+    fs::remove_file(&state)?;
+    log_success!("Kernel {} is down", kernel.version);
     Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     // Return an appropriate exit code
-    let mut exitcode = Success as _;
+    let mut exitcode: i32 = Success as _;
 
     // Obtain the running config
-    let opt = Opt::parse();
+    let mut opt = Opt::parse();
+    opt.gdb |= opt.break_on_load;
+    opt.debug |= opt.gdb;
 
     // Test if file exists
     if !opt.config.exists() {
@@ -170,17 +816,16 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     // Read config file
-    let mut config: Config = toml::from_slice(&read(opt.config)?)?;
+    let mut config: Config = toml::from_slice(&read(&opt.config)?)?;
 
     // Init the cache
     let cache = Cache::new(&shellexpand::tilde(&config.cache).deref());
 
     // Results table
-    let mut table = Table::new();
-    table.add_row(row![Fy->"Version", Fy->"Build", Fy->"Insmod", Fy->"Tests"]);
+    let mut table = results_table();
 
     // Optionally filter for specific version
-    let kernel_iter: Box<dyn Iterator<Item = &mut KConfig>> = match opt.kernel {
+    let mut kernel_iter: Box<dyn Iterator<Item = &mut KConfig>> = match &opt.kernel {
         Some(_) => Box::new(
             config
                 .kernels
@@ -190,55 +835,140 @@ fn main() -> Result<(), Box<dyn Error>> {
         None => Box::new(config.kernels.iter_mut()),
     };
 
-    // Detect host SSH client version (dirty hack)
-    let ssh_version = SshVersion::query()?;
-    log_status!(
-        "Host SSH client version: {:?}, legacy: {:?}",
-        ssh_version.version(),
-        ssh_version.is_legacy()
+    // Artifacts of this run
+    let rundir = opt.output.join(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs()
+            .to_string(),
     );
 
-    for kernel in kernel_iter {
-        // Download or retrieve cached items
-        cache.get(kernel)?;
+    // An editor indexes against a single kernel
+    if let Some(Cmd::Compdb) = opt.command {
+        let Some(kernel) = kernel_iter.next() else {
+            log_error!(
+                "No kernel matches {:?}",
+                opt.kernel.as_deref().unwrap_or_default()
+            );
+            std::process::exit(BadFilePath as i32);
+        };
+        compdb(&config.module, kernel, &cache, &rundir, &opt)?;
+        return Ok(());
+    }
+
+    // Quick compile check, nothing is booted
+    if opt.build_only {
+        let status = build_only(&config.module, kernel_iter, &cache, &rundir, &opt)?;
+        std::process::exit(status as i32);
+    }
 
-        // Start qemu with the config
-        let handle = Qemu::start(kernel, opt.debug, ssh_version.is_legacy())?;
+    let mut opts = QemuOpts {
+        debug: opt.debug,
+        detach: false,
+        subprocess_ssh: opt.subprocess_ssh,
+        snapshots: !config.module.test_cases.is_empty(),
+    };
 
-        // Create results row
-        let mut row = row![kernel.version, Fb->"N/A", "N/A".blue(), "N/A".blue()];
-        match test(&config.module, kernel, &handle, opt.debug) {
-            Err(x) if x.downcast_ref::<NixModuleError>() == Some(&BuildError) => {
-                row.set_cell(cell!(Fr->"Failed"), 1)?;
-                exitcode = BuildError as _;
+    match opt.command {
+        Some(Cmd::Shell) => {
+            opts.debug = true;
+            for kernel in kernel_iter {
+                shell(kernel, &cache, &rundir, opts)?;
+            }
+            return Ok(());
+        }
+        Some(Cmd::Up) => {
+            opts.debug = true;
+            opts.detach = true;
+            for kernel in kernel_iter {
+                up(kernel, &cache, &rundir, &opt.output, opts)?;
             }
-            Err(x) if x.downcast_ref::<NixModuleError>() == Some(&InsmodError) => {
-                row.set_cell(cell!(Fg->"Ok"), 1)?;
-                row.set_cell(cell!(Fr->"Failed"), 2)?;
-                exitcode = InsmodError as _;
+            return Ok(());
+        }
+        Some(Cmd::Down) => {
+            for kernel in kernel_iter {
+                down(kernel, &opt.output)?;
             }
-            Err(x) if x.downcast_ref::<NixModuleError>() == Some(&TestError) => {
-                row.set_cell(cell!(Fg->"Ok"), 1)?;
-                row.set_cell(cell!(Fg->"Ok"), 2)?;
-                row.set_cell(cell!(Fr->"Failed"), 3)?;
-                exitcode = TestError as _;
+            return Ok(());
+        }
+        Some(Cmd::Reload) | Some(Cmd::Compdb) | None => {}
+    }
+
+    // Boot every kernel once and iterate on source changes
+    if opt.watch {
+        return watch(&config.module, kernel_iter, &cache, &rundir, &opt, opts);
+    }
+
+    // Boot artifacts download in the background while building,
+    // a VM re-used by reload needs none of them
+    let reload = matches!(opt.command, Some(Cmd::Reload));
+    let kernels: Vec<&mut KConfig> = kernel_iter.collect();
+    let prefetch = match reload {
+        true => None,
+        false => Some(cache.prefetch(kernels.iter().map(|k| (**k).clone()).collect())),
+    };
+
+    for kernel in kernels {
+        // Headers are all the build needs
+        cache.headers(kernel)?;
+
+        let (handle, status) = match prefetch {
+            // Re-use a running VM
+            None => {
+                cache.vmlinux(kernel)?;
+                let Ok(handle) = Qemu::attach(&state_path(&opt.output, kernel)) else {
+                    log_error!("Kernel {} is not up, skipping", kernel.version);
+                    continue;
+                };
+                let status = check(&config.module, kernel, &handle, &opt, &mut table)?;
+                (Some(handle), status)
             }
-            Ok(_) => {
-                row.set_cell(cell!(Fg->"Ok"), 1)?;
-                row.set_cell(cell!(Fg->"Ok"), 2)?;
-                row.set_cell(cell!(Fg->"Ok"), 3)?;
+            // Only boot once the module built
+            Some(ref prefetch) => {
+                let kdir = rundir.join(&kernel.version);
+                let mut stats = Stats::default();
+                match build(&config.module, kernel, &opt, &kdir, &mut stats) {
+                    Err(e) => {
+                        prefetch.skip(kernel);
+                        let status = status_of(&Err(e));
+                        add_row(&mut table, kernel, None, &stats, status)?;
+                        (None, status)
+                    }
+                    Ok((built, loader)) => {
+                        prefetch.wait(kernel)?;
+                        let handle = Qemu::start(kernel, &kdir, opts)?;
+                        let result = test(&config.module, kernel, &handle, &opt, &built, &loader);
+                        let status = status_of(&result);
+                        add_row(&mut table, kernel, Some(handle.accel()), &stats, status)?;
+                        (Some(handle), status)
+                    }
+                }
             }
-            _ => {}
+        };
+        let failed = status != Success;
+        if failed {
+            exitcode = status as _;
         }
-        table.add_row(row);
 
-        // Go interactive if a debug session was requested
-        if exitcode == Success as _ && opt.debug {
+        // Nothing to inspect when the build failed
+        let Some(handle) = handle else {
+            continue;
+        };
+        let kdir = handle.rundir().to_path_buf();
+
+        // Go interactive if a debug session was requested,
+        // or to inspect a failure
+        if opt.debug || (failed && opt.shell_on_failure) {
+            if !failed && opt.gdb {
+                GdbScript::launch(&kdir.join("gdbinit")).unwrap_or_else(|e| println!("{:?}", e));
+            }
             handle.interact().unwrap_or_else(|e| println!("{:?}", e));
         }
 
-        // Wait and stop qemu
-        handle.stop()?;
+        // Wait and stop qemu, VMs kept alive by `up` stay running
+        if !reload {
+            handle.stop()?;
+        }
     }
 
     if !opt.debug {
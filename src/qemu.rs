@@ -1,27 +1,150 @@
 use crate::errors::NixModuleError::*;
-use crate::utils::print_output;
+use crate::gdb::free_port;
+use crate::ssh::{Native, SshVersion};
+use crate::utils::{print_output, shell_quote};
 use crate::KConfig;
 use colored::*;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::cell::{OnceCell, RefCell};
 use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
 use std::io::Read;
 use std::net::{SocketAddr, TcpStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+mod qmp;
+use qmp::Qmp;
+
+/// Boot timeout when the kernel doesn't configure one, in seconds
+const DEFAULT_TIMEOUT: u64 = 60;
+
+/// The default timeout is multiplied by this without hardware acceleration
+const TCG_TIMEOUT_SCALE: u64 = 5;
+
+/// Accelerator used to run a guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Accel {
+    Kvm,
+    Tcg,
+}
+
+impl Accel {
+    /// Pick the accelerator for a kernel, probing /dev/kvm up front
+    /// rather than letting qemu fail after spawn.
+    pub fn select(kernel: &KConfig) -> Result<Self, Box<dyn Error>> {
+        if !kernel.kvm {
+            return Ok(Self::Tcg);
+        }
+        if !kernel.arch.is_native() {
+            log_status!(
+                "Disabling kvm for {:?} guest on a foreign host",
+                kernel.arch
+            );
+            return Ok(Self::Tcg);
+        }
+        match Self::kvm_available() {
+            true => Ok(Self::Kvm),
+            false if kernel.tcg_fallback => {
+                log_error!("/dev/kvm is not accessible, falling back to TCG (this will be slow)");
+                Ok(Self::Tcg)
+            }
+            false => {
+                log_error!("/dev/kvm is not accessible and tcg_fallback is disabled");
+                Err(KvmError.into())
+            }
+        }
+    }
+
+    /// Test if the current user can open /dev/kvm
+    fn kvm_available() -> bool {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .is_ok()
+    }
+
+    /// Boot timeout for a kernel, scaled when running under TCG. A timeout
+    /// configured for a `kvm = false` kernel already accounts for TCG and
+    /// is used as is, one tuned for KVM is scaled when KVM is unavailable.
+    fn timeout(&self, kernel: &KConfig) -> Duration {
+        let timeout = kernel.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        match self {
+            Self::Tcg if kernel.timeout.is_none() || kernel.kvm => {
+                Duration::new(timeout * TCG_TIMEOUT_SCALE, 0)
+            }
+            _ => Duration::new(timeout, 0),
+        }
+    }
+}
+
+impl fmt::Display for Accel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Kvm => write!(f, "kvm"),
+            Self::Tcg => write!(f, "tcg"),
+        }
+    }
+}
+
+/// Options controlling how a VM is started
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QemuOpts {
+    /// Start a gdb server on a free port
+    pub debug: bool,
+    /// Keep the VM running after nixmodule exits
+    pub detach: bool,
+    /// Use the ssh and scp binaries instead of the built-in client
+    pub subprocess_ssh: bool,
+    /// Boot from a qcow2 overlay so the VM can be snapshotted
+    pub snapshots: bool,
+}
+
+/// Everything needed to re-attach to a detached VM
+#[derive(Serialize, Deserialize)]
+struct State {
+    pid: u32,
+    qmp_path: PathBuf,
+    overlay: Option<PathBuf>,
+    rundir: PathBuf,
+    gdbport: Option<u16>,
+    accel: Accel,
+    sshkey: String,
+    sshport: String,
+    #[serde(default)]
+    subprocess_ssh: bool,
+}
+
 pub struct Qemu {
-    handle: Child,
+    handle: Option<Child>,
+    pid: u32,
+    qmp: RefCell<Qmp>,
+    qmp_path: PathBuf,
+    overlay: Option<PathBuf>,
+    rundir: PathBuf,
+    gdbport: Option<u16>,
+    accel: Accel,
     sshkey: String,
     sshport: String,
-    legacy_ssh: bool,
+    /// The host scp predates -O, probed once the ssh binaries are used
+    legacy_ssh: OnceCell<bool>,
+    subprocess_ssh: bool,
+    native: RefCell<Option<Native>>,
 }
 
 impl Qemu {
     /// Start Qemu with the provided configuration
-    pub fn start(kernel: &KConfig, debug: bool, legacy: bool) -> Result<Self, Box<dyn Error>> {
-        let timeout = Duration::new(kernel.timeout.map_or(60, |v| v), 0);
-        let mut qemu = Command::new(&kernel.runner);
+    pub fn start(kernel: &KConfig, rundir: &Path, opts: QemuOpts) -> Result<Self, Box<dyn Error>> {
+        let arch = kernel.arch;
+        let accel = Accel::select(kernel)?;
+        let timeout = accel.timeout(kernel);
+        let mut qemu = Command::new(kernel.runner.as_deref().unwrap_or(arch.runner()));
 
         // Generate random high port for ssh
         let port: u16 = rand::thread_rng().gen_range(1025..=65535);
@@ -37,51 +160,274 @@ impl Qemu {
         }
 
         // KVM?
-        if kernel.kvm {
+        let kvm = accel == Accel::Kvm;
+        if kvm {
             qemu.arg("-enable-kvm");
         }
 
         // Start gdbserver in debug mode
-        if debug {
-            qemu.arg("-s");
+        let gdbport = match opts.debug {
+            true => Some(free_port()?),
+            false => None,
+        };
+        if let Some(port) = gdbport {
+            qemu.args(["-gdb", &format!("tcp:127.0.0.1:{}", port)]);
+        }
+
+        // Own process group, so it outlives us and our terminal's signals
+        if opts.detach {
+            qemu.process_group(0);
         }
 
+        // Console output and crash artifacts are kept per run
+        fs::create_dir_all(rundir)?;
+        let serial = format!("file:{}", rundir.join("console.log").display());
+
+        // Run on a throwaway qcow2 overlay when the VM is snapshotted,
+        // otherwise let qemu discard writes so the base image stays pristine
+        let overlay = match opts.snapshots {
+            true => {
+                let overlay = std::env::temp_dir().join(format!("nixmodule-{}.qcow2", port));
+                Self::create_overlay(&kernel.disk.path, &overlay)?;
+                Some(overlay)
+            }
+            false => None,
+        };
+        let drive = match overlay {
+            Some(ref overlay) => arch.drive_args(overlay.to_str().ok_or(BadFilePath)?, "qcow2"),
+            None => {
+                qemu.arg("-snapshot");
+                arch.drive_args(&kernel.disk.path, "raw")
+            }
+        };
+
+        // Control channel, any stale socket is left from a crashed run
+        let qmp_path = std::env::temp_dir().join(format!("nixmodule-{}.qmp", port));
+        let _ = fs::remove_file(&qmp_path);
+        let qmp_arg = format!("unix:{},server=on,wait=off", qmp_path.display());
+
         let fwd = format!("user,host=10.0.2.10,hostfwd=tcp:127.0.0.1:{}-:22", port);
         let bootargs = format!(
-            "console=ttyS0 root={} earlyprintk=serial net.ifnames=0 nokaslr",
-            kernel.disk.boot
+            "console={} root={} {} net.ifnames=0 nokaslr",
+            arch.console(),
+            kernel.disk.boot,
+            arch.earlycon(),
         );
 
         // Kick of the process
+        let mut handle = qemu
+            .args(arch.machine_args(kvm))
+            .args(["-m", "512M", "-smp", "2"])
+            .args(["-kernel", &kernel.kernel])
+            .args(["-append", &bootargs])
+            .args(drive)
+            .args(["-net", &fwd])
+            .args(["-net", &format!("nic,model={}", arch.nic())])
+            .args(["-display", "none", "-monitor", "none"])
+            .args(["-serial", &serial])
+            .args(["-pidfile", "vm.pid"])
+            .args(["-qmp", &qmp_arg])
+            .args(["-device", arch.pvpanic()])
+            .arg("-no-shutdown")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .or(Err(QemuError))?;
+
+        let qmp = match Qmp::connect(&qmp_path, Duration::new(10, 0)) {
+            Ok(qmp) => qmp,
+            Err(e) => {
+                let _ = handle.kill();
+                let _ = handle.wait();
+                if let Some(ref overlay) = overlay {
+                    let _ = fs::remove_file(overlay);
+                }
+                return Err(e);
+            }
+        };
+
         let res = Self {
-            handle: qemu
-                .args(["-m", "512M", "-smp", "2"])
-                .args(["-kernel", &kernel.kernel])
-                .args(["-append", &bootargs])
-                .args(["-drive", &format!("file={},format=raw", &kernel.disk.path)])
-                .args(["-net", &fwd])
-                .args(["-net", "nic,model=e1000"])
-                .arg("-nographic")
-                .args(["-pidfile", "vm.pid"])
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .spawn()
-                .or(Err(QemuError))?,
+            pid: handle.id(),
+            handle: Some(handle),
+            qmp: RefCell::new(qmp),
+            qmp_path,
+            overlay,
+            rundir: rundir.to_path_buf(),
+            gdbport,
+            accel,
             sshkey: kernel.disk.sshkey.clone(),
             sshport: port.to_string(),
-            legacy_ssh: legacy,
+            legacy_ssh: OnceCell::new(),
+            subprocess_ssh: opts.subprocess_ssh,
+            native: RefCell::new(None),
         };
 
         log_status!("Waiting for VM to boot...");
         if let Err(e) = res.wait_for_boot(port, timeout) {
+            // A guest that's merely slow has nothing worth dumping
+            if res.panicked() {
+                let _ = res.collect_crash(kernel);
+            }
             res.stop()?;
             return Err(e);
         }
+        res.connect();
 
         Ok(res)
     }
 
+    /// Record the VM's state so it can be re-attached to later
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let state = State {
+            pid: self.pid,
+            qmp_path: self.qmp_path.clone(),
+            overlay: self.overlay.clone(),
+            rundir: self.rundir.clone(),
+            gdbport: self.gdbport,
+            accel: self.accel,
+            sshkey: self.sshkey.clone(),
+            sshport: self.sshport.clone(),
+            subprocess_ssh: self.subprocess_ssh,
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(&state)?)?;
+        Ok(())
+    }
+
+    /// Attach to a VM previously started with `detach` and saved
+    pub fn attach(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let state: State = serde_json::from_slice(&fs::read(path)?)?;
+        if !Self::alive(state.pid) {
+            return Err(QemuError.into());
+        }
+        let qmp = Qmp::connect(&state.qmp_path, Duration::new(5, 0))?;
+        let res = Self {
+            handle: None,
+            pid: state.pid,
+            qmp: RefCell::new(qmp),
+            qmp_path: state.qmp_path,
+            overlay: state.overlay,
+            rundir: state.rundir,
+            gdbport: state.gdbport,
+            accel: state.accel,
+            sshkey: state.sshkey,
+            sshport: state.sshport,
+            legacy_ssh: OnceCell::new(),
+            subprocess_ssh: state.subprocess_ssh,
+            native: RefCell::new(None),
+        };
+        res.connect();
+        Ok(res)
+    }
+
+    /// Open the native ssh session, staying with the ssh and scp
+    /// binaries when disabled or when the guest won't have it
+    fn connect(&self) {
+        if self.subprocess_ssh {
+            self.legacy_ssh();
+            return;
+        }
+        match Native::connect(&self.sshport, &self.sshkey) {
+            Ok(native) => {
+                self.native.replace(Some(native));
+            }
+            Err(e) => {
+                log_error!("Native ssh failed ({}), falling back to the ssh binary", e);
+                self.native.replace(None);
+                self.legacy_ssh();
+            }
+        }
+    }
+
+    /// Detect the host SSH client version and scp capabilities, only
+    /// needed once falling back to the binaries
+    fn legacy_ssh(&self) -> bool {
+        *self.legacy_ssh.get_or_init(|| {
+            let version = match SshVersion::query() {
+                Ok(version) => version,
+                Err(e) => {
+                    log_error!("Could not run the host ssh client: {}", e);
+                    return true;
+                }
+            };
+            log_status!(
+                "Host SSH client: {}, legacy scp: {:?}",
+                version,
+                version.is_legacy()
+            );
+            if !version.is_openssh() {
+                log_error!(
+                    "Only OpenSSH clients are supported, {} may not work",
+                    version
+                );
+            }
+            version.is_legacy()
+        })
+    }
+
+    /// Test if a process is still running
+    fn alive(pid: u32) -> bool {
+        Path::new(&format!("/proc/{}", pid)).exists()
+    }
+
+    /// Create a qcow2 overlay backed by the raw disk image
+    fn create_overlay(base: &str, overlay: &Path) -> Result<(), Box<dyn Error>> {
+        let base = fs::canonicalize(base)?;
+        let res = Command::new("qemu-img")
+            .args(["create", "-q", "-f", "qcow2", "-F", "raw", "-b"])
+            .arg(&base)
+            .arg(overlay)
+            .output()?;
+
+        match res.status.success() {
+            true => Ok(()),
+            false => {
+                print_output(std::str::from_utf8(&res.stderr)?);
+                Err(QemuError.into())
+            }
+        }
+    }
+
+    /// Save the current state of the VM as a named snapshot
+    pub fn snapshot(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        if self.overlay.is_none() {
+            log_error!("VM was started without snapshot support");
+            return Err(QemuError.into());
+        }
+        log_status!("Saving snapshot {}", name);
+        self.qmp.borrow_mut().savevm(name)
+    }
+
+    /// Roll the VM back to a named snapshot
+    pub fn restore(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        log_status!("Restoring snapshot {}", name);
+        self.qmp.borrow_mut().loadvm(name)?;
+
+        // The guest's end of the session was rolled back with it
+        if self.native.borrow_mut().take().is_some() {
+            self.connect();
+        }
+        Ok(())
+    }
+
+    /// Directory holding this VM's logs and artifacts
+    pub fn rundir(&self) -> &Path {
+        &self.rundir
+    }
+
+    /// Port of the gdb server, only available in debug mode
+    pub fn gdbport(&self) -> Option<u16> {
+        self.gdbport
+    }
+
+    /// Accelerator this VM was started with
+    pub fn accel(&self) -> Accel {
+        self.accel
+    }
+
     /// hacky workaround to wait for boot to finish
     fn wait_for_boot(&self, port: u16, timeout: Duration) -> Result<(), Box<dyn Error>> {
         // Wait until boot is complete/port is open
@@ -91,6 +437,10 @@ impl Qemu {
             if start.elapsed() > timeout {
                 return Err(TimeoutError.into());
             }
+            if self.panicked() {
+                log_error!("Guest kernel panicked during boot");
+                return Err(QemuError.into());
+            }
             sleep(Duration::new(7, 0));
         }
 
@@ -108,18 +458,53 @@ impl Qemu {
         Ok(())
     }
 
-    pub fn runcmd(&self, cmd: &str) -> Result<(), Box<dyn Error>> {
-        log_status!("Running {}", cmd);
-        let res = Command::new("ssh")
-            .args(["-i", &self.sshkey])
+    /// Base ssh command for the running VM
+    fn ssh(&self) -> Command {
+        let mut ssh = Command::new("ssh");
+        ssh.args(["-i", &self.sshkey])
             .args(["-p", &self.sshport])
             .args(["-oStrictHostKeyChecking=no"])
-            .arg("root@localhost")
-            .arg(cmd)
-            .output()?;
+            .args(["-oConnectTimeout=10"])
+            .args(["-oServerAliveInterval=5", "-oServerAliveCountMax=3"])
+            .arg("root@localhost");
+        ssh
+    }
+
+    /// Shell command line running `cmd` on the VM
+    pub fn ssh_cmdline(&self, cmd: &str) -> String {
+        let ssh = self.ssh();
+        std::iter::once(ssh.get_program())
+            .chain(ssh.get_args())
+            .map(|a| shell_quote(&a.to_string_lossy()))
+            .chain(std::iter::once(shell_quote(cmd)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn runcmd(&self, cmd: &str) -> Result<(), Box<dyn Error>> {
+        self.runcmd_output(cmd)?;
+        Ok(())
+    }
+
+    /// Run a command on the VM and return its stdout
+    pub fn runcmd_output(&self, cmd: &str) -> Result<String, Box<dyn Error>> {
+        log_status!("Running {}", cmd);
+        if let Some(ref native) = *self.native.borrow() {
+            let res = native.exec(cmd, false, &|| !self.panicked())?;
+            return match res.status {
+                0 => Ok(res.stdout),
+                status => {
+                    print_output(&res.stderr);
+                    print_output(&res.stdout);
+                    log_error!("{} exited with status {}", cmd, status);
+                    Err(SshError.into())
+                }
+            };
+        }
+        let res = self.ssh().arg(cmd).output()?;
 
         match res.status.success() {
-            true => Ok(()),
+            true => Ok(String::from_utf8_lossy(&res.stdout).into_owned()),
             false => {
                 print_output(std::str::from_utf8(&res.stderr)?);
                 print_output(std::str::from_utf8(&res.stdout)?);
@@ -128,16 +513,38 @@ impl Qemu {
         }
     }
 
+    /// Run a command on the VM, showing its output as it runs
+    pub fn runcmd_streamed(&self, cmd: &str) -> Result<(), Box<dyn Error>> {
+        log_status!("Running {}", cmd);
+        let status = match *self.native.borrow() {
+            Some(ref native) => native.exec(cmd, true, &|| !self.panicked())?.status,
+            None => self.ssh().arg(cmd).status()?.code().unwrap_or(-1),
+        };
+        match status {
+            0 => Ok(()),
+            status => {
+                log_error!("{} exited with status {}", cmd, status);
+                Err(SshError.into())
+            }
+        }
+    }
+
     /// Transfer a file into the running VM
     ///
     /// The scp client since 9.0 from using the legacy scp/rcp protocol
     /// to using the SFTP protocol by default.
     pub fn transfer(&self, local: &str, remote: &str) -> Result<(), Box<dyn Error>> {
         log_status!("Uploading {}", local);
+        if let Some(ref native) = *self.native.borrow() {
+            return native.upload(Path::new(local), remote).map_err(|e| {
+                log_error!("Upload of {} failed: {}", local, e);
+                SshError.into()
+            });
+        }
         let mut builder = Command::new("scp");
 
         // Max compat for newest ssh clients
-        if !self.legacy_ssh {
+        if !self.legacy_ssh() {
             builder.arg("-O");
         }
 
@@ -162,17 +569,16 @@ impl Qemu {
     /// Enter an interactive shell on the running VM
     /// does not return until the shell exits
     pub fn interact(&self) -> Result<(), Box<dyn Error>> {
-        let mut session = Command::new("ssh")
-            .args(["-i", &self.sshkey])
-            .args(["-p", &self.sshport])
-            .args(["-oStrictHostKeyChecking=no"])
-            .arg("root@localhost")
+        if let Some(ref native) = *self.native.borrow() {
+            return native.interact(&|| !self.panicked());
+        }
+        let mut session = self
+            .ssh()
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
             .stdin(Stdio::inherit())
             .spawn()?;
 
-        log_status!("Use 'target remote localhost:1234' to connect to the gdb server");
         match session.wait() {
             Ok(_status) => Ok(()),
             Err(e) => {
@@ -182,10 +588,128 @@ impl Qemu {
         }
     }
 
-    /// Stop the background qemu instance
+    /// Whether the guest still answers commands over ssh
+    pub fn reachable(&self) -> bool {
+        match *self.native.borrow() {
+            Some(ref native) => native
+                .exec("true", false, &|| !self.panicked())
+                .is_ok_and(|res| res.status == 0),
+            None => self
+                .ssh()
+                .arg("true")
+                .output()
+                .is_ok_and(|res| res.status.success()),
+        }
+    }
+
+    /// Test if the guest kernel has panicked
+    pub fn panicked(&self) -> bool {
+        self.qmp.borrow_mut().panicked().unwrap_or(false)
+    }
+
+    /// Capture a vmcore and screendump of a panicked guest into the run
+    /// directory, next to a link to the kernel's vmlinux. Open it afterwards with
+    /// `crash vmlinux vmcore`.
+    pub fn collect_crash(&self, kernel: &KConfig) -> Result<PathBuf, Box<dyn Error>> {
+        let vmcore = self.rundir.join("vmcore");
+        log_status!("Dumping guest memory to {:?}", vmcore);
+        let mut qmp = self.qmp.borrow_mut();
+        qmp.dump_memory(&vmcore)?;
+
+        // Guests without a display, such as aarch64 virt, have no screen
+        let _ = qmp.screendump(&self.rundir.join("screen.ppm"));
+
+        // crash needs the uncompressed image with symbols, not the bzImage
+        let Some(ref vmlinux) = kernel.vmlinux else {
+            log_error!(
+                "No vmlinux configured for {}, crash can't open the vmcore without one",
+                kernel.version
+            );
+            return Ok(vmcore);
+        };
+        let link = self.rundir.join("vmlinux");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(fs::canonicalize(vmlinux)?, link)?;
+        Ok(vmcore)
+    }
+
+    /// Stop the background qemu instance, attempting a graceful
+    /// powerdown before asking qemu to quit.
     pub fn stop(mut self) -> Result<(), Box<dyn Error>> {
-        self.handle.kill()?;
-        self.handle.wait()?;
+        {
+            let mut qmp = self.qmp.borrow_mut();
+            if qmp.status().is_ok_and(|s| s == "running") {
+                let _ = qmp.powerdown(Duration::new(5, 0));
+            }
+            let _ = qmp.quit();
+        }
+
+        // Fall back to killing it outright
+        let start = Instant::now();
+        loop {
+            let exited = match self.handle {
+                Some(ref mut child) => child.try_wait()?.is_some(),
+                None => !Self::alive(self.pid),
+            };
+            if exited {
+                break;
+            }
+            if start.elapsed() > Duration::new(5, 0) {
+                match self.handle {
+                    Some(ref mut child) => {
+                        child.kill()?;
+                        child.wait()?;
+                    }
+                    None => {
+                        Command::new("kill")
+                            .args(["-9", &self.pid.to_string()])
+                            .status()?;
+                    }
+                }
+                break;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        let _ = fs::remove_file(&self.qmp_path);
+        if let Some(ref overlay) = self.overlay {
+            let _ = fs::remove_file(overlay);
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kernel(extra: &str) -> KConfig {
+        toml::from_str(&format!(
+            "version = \"6.1.0\"\n\
+             url_base = \"http://localhost\"\n\
+             headers = \"headers.tar.gz\"\n\
+             kernel = \"bzImage\"\n\
+             {}\n\
+             [disk]\n\
+             url_base = \"http://localhost\"\n\
+             path = \"disk.img\"\n\
+             sshkey = \"id_rsa\"\n\
+             boot = \"/dev/sda\"\n",
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn scales_timeouts_when_kvm_is_unavailable() {
+        let secs = |accel: Accel, extra| accel.timeout(&kernel(extra)).as_secs();
+        assert_eq!(secs(Accel::Kvm, ""), DEFAULT_TIMEOUT);
+        assert_eq!(secs(Accel::Tcg, ""), DEFAULT_TIMEOUT * TCG_TIMEOUT_SCALE);
+        assert_eq!(secs(Accel::Kvm, "timeout = 30"), 30);
+        assert_eq!(secs(Accel::Tcg, "timeout = 30"), 30 * TCG_TIMEOUT_SCALE);
+        assert_eq!(secs(Accel::Tcg, "timeout = 600\nkvm = false"), 600);
+        assert_eq!(
+            secs(Accel::Tcg, "kvm = false"),
+            DEFAULT_TIMEOUT * TCG_TIMEOUT_SCALE
+        );
+    }
+}
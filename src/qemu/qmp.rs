@@ -0,0 +1,259 @@
+use crate::errors::NixModuleError::*;
+use colored::*;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Longest a command may take to answer, dumps and snapshots
+/// of a 512M guest finish well within this
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Minimal client for the QEMU Machine Protocol
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    events: Vec<Value>,
+}
+
+impl Qmp {
+    /// Connect to a QMP socket, waiting for qemu to create it,
+    /// and negotiate capabilities.
+    pub fn connect<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        let start = Instant::now();
+        let stream = loop {
+            match UnixStream::connect(path.as_ref()) {
+                Ok(stream) => break stream,
+                Err(_) if start.elapsed() < timeout => sleep(Duration::from_millis(100)),
+                Err(_) => return Err(QemuError.into()),
+            }
+        };
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        let mut res = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            events: Vec::new(),
+        };
+
+        // Server greeting, then leave negotiation mode
+        res.read()?;
+        res.execute("qmp_capabilities", None)?;
+        Ok(res)
+    }
+
+    /// Read a single message from the socket, a wedged qemu
+    /// is reported once the reply timeout expires
+    fn read(&mut self) -> Result<Value, Box<dyn Error>> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => return Err(QemuError.into()),
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                log_error!("qemu did not answer within {:?}", REPLY_TIMEOUT);
+                return Err(QemuError.into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Execute a command and return its result. Asynchronous
+    /// events received in the meantime are queued.
+    pub fn execute(&mut self, cmd: &str, args: Option<Value>) -> Result<Value, Box<dyn Error>> {
+        let mut msg = json!({ "execute": cmd });
+        if let Some(args) = args {
+            msg["arguments"] = args;
+        }
+        writeln!(self.writer, "{}", msg)?;
+
+        loop {
+            let mut resp = self.read()?;
+            if resp.get("event").is_some() {
+                self.events.push(resp);
+                continue;
+            }
+            if let Some(err) = resp.get("error") {
+                return Err(format!("QMP {} failed: {}", cmd, err["desc"]).into());
+            }
+            return Ok(resp["return"].take());
+        }
+    }
+
+    /// Run a human monitor command, for features without a QMP equivalent
+    pub fn hmp(&mut self, cmdline: &str) -> Result<String, Box<dyn Error>> {
+        let res = self.execute(
+            "human-monitor-command",
+            Some(json!({ "command-line": cmdline })),
+        )?;
+        let out = res.as_str().unwrap_or_default().to_string();
+        match out.trim().is_empty() {
+            true => Ok(out),
+            false => Err(format!("{}: {}", cmdline, out.trim()).into()),
+        }
+    }
+
+    /// Current run state of the guest, i.e. "running" or "guest-panicked"
+    pub fn status(&mut self) -> Result<String, Box<dyn Error>> {
+        let res = self.execute("query-status", None)?;
+        Ok(res["status"].as_str().unwrap_or("unknown").to_string())
+    }
+
+    /// Test if the guest reported a panic through pvpanic
+    pub fn panicked(&mut self) -> Result<bool, Box<dyn Error>> {
+        let status = self.status()?;
+        let event = self
+            .events
+            .iter()
+            .any(|e| e["event"].as_str() == Some("GUEST_PANICKED"));
+        Ok(event || status == "guest-panicked")
+    }
+
+    /// Request an ACPI shutdown and wait for the guest to power off
+    pub fn powerdown(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        self.execute("system_powerdown", None)?;
+        let start = Instant::now();
+        while self.status()? != "shutdown" {
+            if start.elapsed() > timeout {
+                return Err(TimeoutError.into());
+            }
+            sleep(Duration::from_millis(250));
+        }
+        Ok(())
+    }
+
+    /// Terminate qemu
+    pub fn quit(&mut self) -> Result<(), Box<dyn Error>> {
+        self.execute("quit", None)?;
+        Ok(())
+    }
+
+    /// Save a screenshot of the primary display as PPM
+    pub fn screendump(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.execute("screendump", Some(json!({ "filename": path })))?;
+        Ok(())
+    }
+
+    /// Write a vmcore of guest memory to the provided path
+    pub fn dump_memory(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let protocol = format!("file:{}", path.display());
+        self.execute(
+            "dump-guest-memory",
+            Some(json!({ "paging": false, "protocol": protocol })),
+        )?;
+        Ok(())
+    }
+
+    /// Save a VM snapshot, requires a qcow2 backed disk
+    pub fn savevm(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.hmp(&format!("savevm {}", name))?;
+        Ok(())
+    }
+
+    /// Restore a VM snapshot taken with savevm and resume the guest.
+    /// Panics reported before the snapshot was loaded are forgotten.
+    pub fn loadvm(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.hmp(&format!("loadvm {}", name))?;
+        self.events
+            .retain(|e| e["event"].as_str() != Some("GUEST_PANICKED"));
+
+        // loadvm only restarts guests that were running, not panicked ones
+        if self.status()? != "running" {
+            self.execute("cont", None)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::thread::{self, JoinHandle};
+
+    /// Fake qemu answering each expected command with scripted
+    /// messages, events included
+    fn serve(name: &str, script: Vec<(&'static str, Vec<Value>)>) -> (PathBuf, JoinHandle<()>) {
+        let path =
+            std::env::temp_dir().join(format!("nixmodule-{}-{}.qmp", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writeln!(writer, "{}", json!({ "QMP": { "version": {} } })).unwrap();
+            for (cmd, replies) in script {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let msg: Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(msg["execute"], cmd);
+                for reply in replies {
+                    writeln!(writer, "{}", reply).unwrap();
+                }
+            }
+        });
+        (path, server)
+    }
+
+    fn status(status: &str) -> Vec<Value> {
+        vec![json!({ "return": { "status": status, "running": status == "running" } })]
+    }
+
+    #[test]
+    fn forgets_panics_once_a_snapshot_is_loaded() {
+        let panicked = json!({ "event": "GUEST_PANICKED", "data": { "action": "pause" } });
+        let (path, server) = serve(
+            "loadvm",
+            vec![
+                ("qmp_capabilities", vec![json!({ "return": {} })]),
+                (
+                    "query-status",
+                    vec![panicked, json!({ "return": { "status": "running" } })],
+                ),
+                ("human-monitor-command", vec![json!({ "return": "" })]),
+                ("query-status", status("running")),
+                ("query-status", status("running")),
+            ],
+        );
+        let mut qmp = Qmp::connect(&path, Duration::new(5, 0)).unwrap();
+        assert!(qmp.panicked().unwrap());
+        qmp.loadvm("nixmodule-ready").unwrap();
+        assert!(!qmp.panicked().unwrap());
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn resumes_a_guest_that_panicked_before_the_restore() {
+        // The first test case panicked the guest, pvpanic paused it
+        let (path, server) = serve(
+            "cont",
+            vec![
+                ("qmp_capabilities", vec![json!({ "return": {} })]),
+                ("query-status", status("guest-panicked")),
+                ("human-monitor-command", vec![json!({ "return": "" })]),
+                ("query-status", status("guest-panicked")),
+                (
+                    "cont",
+                    vec![json!({ "event": "RESUME" }), json!({ "return": {} })],
+                ),
+                ("query-status", status("running")),
+            ],
+        );
+        let mut qmp = Qmp::connect(&path, Duration::new(5, 0)).unwrap();
+        assert!(qmp.panicked().unwrap());
+        qmp.loadvm("nixmodule-ready").unwrap();
+        assert!(!qmp.panicked().unwrap());
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,33 +1,475 @@
+use crate::errors::NixModuleError::*;
+use colored::*;
+use ssh2::{ExtendedData, FileStat, OpenFlags, OpenType, Session};
 use std::error::Error;
-use std::process::Command;
-use std::str::FromStr;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 
-/// Wrapper to detect host SSH client version
+/// Wrapper to detect host SSH client version and capabilities
 pub struct SshVersion {
-    version: f32,
+    client: String,
+    version: Option<(u32, u32)>,
+    scp_legacy_flag: bool,
 }
 
 impl SshVersion {
-    /// Query the local ssh client version
+    /// Query the local ssh client version, and probe whether its scp
+    /// accepts -O. Clients that can't be parsed don't stop the run.
     pub fn query() -> Result<Self, Box<dyn Error>> {
-        let cmd = r"ssh -V 2>&1 | awk -F'[_,]' '{print $2+0}'";
-        let output = Command::new("/bin/sh").arg("-c").arg(cmd).output()?;
-        let version_str = std::str::from_utf8(&output.stdout)?.to_string();
+        // Written to stderr, e.g. "OpenSSH_9.2p1 Debian-2, OpenSSL 3.0.11"
+        let output = Command::new("ssh").arg("-V").output()?;
+        let banner = String::from_utf8_lossy(&output.stderr).into_owned()
+            + &String::from_utf8_lossy(&output.stdout);
+        let (client, version) = Self::parse(banner.lines().next().unwrap_or_default());
         Ok(Self {
-            version: f32::from_str(version_str.trim())?,
+            client,
+            version,
+            scp_legacy_flag: Self::probe_scp(),
         })
     }
 
-    pub fn version(&self) -> &f32 {
-        &self.version
+    /// Split a banner such as "OpenSSH_10.0p2" or "Dropbear v2022.83"
+    /// into the client name and its major and minor version
+    fn parse(banner: &str) -> (String, Option<(u32, u32)>) {
+        let client = banner
+            .split(|c: char| c == '_' || c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        // First run of digits and dots after the name
+        let rest = &banner[client.len()..];
+        let start = rest.find(|c: char| c.is_ascii_digit());
+        let version = start.and_then(|start| {
+            let digits: String = rest[start..]
+                .chars()
+                .take_while(|c| c.is_ascii_digit() || *c == '.')
+                .collect();
+            let mut parts = digits.split('.').map(|p| p.parse::<u32>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(major)), Some(Ok(minor))) => Some((major, minor)),
+                (Some(Ok(major)), None) => Some((major, 0)),
+                _ => None,
+            }
+        });
+        (client, version)
+    }
+
+    /// Whether scp understands -O, by copying /dev/null locally with it.
+    /// Clients that don't know the option fail before copying anything.
+    fn probe_scp() -> bool {
+        let probe = std::env::temp_dir().join(format!("nixmodule-scp-{}", std::process::id()));
+        let res = Command::new("scp")
+            .arg("-O")
+            .arg("/dev/null")
+            .arg(&probe)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        let _ = std::fs::remove_file(&probe);
+        res.is_ok_and(|status| status.success())
+    }
+
+    /// Whether the client is OpenSSH, the only one whose flags are relied on
+    pub fn is_openssh(&self) -> bool {
+        self.client == "OpenSSH"
     }
 
-    /// Test if the ssh version requires legacy support for max compatibility.
-    /// This will return true if the host client is older than SSH 9.0.
+    /// Test if the scp client requires legacy support for max compatibility.
+    /// This will return true if the host scp doesn't accept -O.
     ///
     /// The scp client since 9.0 switches from using the legacy scp/rcp protocol
     /// to using the SFTP protocol by default. Reducing compatibility without -O.
     pub fn is_legacy(&self) -> bool {
-        self.version <= 8.9
+        !self.scp_legacy_flag
+    }
+}
+
+impl fmt::Display for SshVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.version {
+            Some((major, minor)) => write!(f, "{} {}.{}", self.client, major, minor),
+            None => write!(f, "{} (unknown version)", self.client),
+        }
+    }
+}
+
+/// Seconds between keepalives on a quiet session, like ServerAliveInterval
+const ALIVE_INTERVAL: u32 = 5;
+
+/// Keepalives left unanswered before the guest is given up on,
+/// like ServerAliveCountMax
+const ALIVE_COUNT_MAX: u32 = 3;
+
+/// Result of a command run over a native session
+pub struct Exec {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Persistent, authenticated SSH session to a VM. Commands, uploads and
+/// interactive shells are channels multiplexed over the one connection.
+pub struct Native {
+    session: Session,
+    /// The session's connection, polled for data from the guest
+    socket: TcpStream,
+}
+
+impl Native {
+    /// Connect to the guest's forwarded port and log in as root with the key
+    pub fn connect(port: &str, key: &str) -> Result<Self, Box<dyn Error>> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port.parse()?));
+        let tcp = TcpStream::connect_timeout(&addr, Duration::new(10, 0))?;
+        let socket = tcp.try_clone()?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+
+        // Bound the handshake, commands may legitimately stay quiet for long
+        session.set_timeout(10_000);
+        session.handshake()?;
+        session.userauth_pubkey_file("root", None, Path::new(key), None)?;
+        if !session.authenticated() {
+            return Err(SshError.into());
+        }
+        session.set_timeout(0);
+        session.set_keepalive(true, ALIVE_INTERVAL);
+        Ok(Self { session, socket })
+    }
+
+    /// Run a command and wait for its exit status. With `stream`, stderr is
+    /// merged into stdout and printed as it arrives. Gives up once the guest
+    /// stops answering keepalives or `alive` reports it gone.
+    pub fn exec(
+        &self,
+        cmd: &str,
+        stream: bool,
+        alive: &dyn Fn() -> bool,
+    ) -> Result<Exec, Box<dyn Error>> {
+        let mut channel = self.session.channel_session()?;
+        if stream {
+            channel.handle_extended_data(ExtendedData::Merge)?;
+        }
+        channel.exec(cmd)?;
+
+        self.session.set_blocking(false);
+        let res = self.output(&channel, stream, alive);
+        self.session.set_blocking(true);
+        let (stdout, stderr) = res?;
+        channel.wait_close()?;
+
+        Ok(Exec {
+            status: channel.exit_status()?,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
+    }
+
+    /// Read stdout and stderr together until the command closes them, so
+    /// neither fills its window while the other is waited on
+    fn output(
+        &self,
+        channel: &ssh2::Channel,
+        stream: bool,
+        alive: &dyn Fn() -> bool,
+    ) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut buf = [0u8; 4096];
+        let mut heard = Instant::now();
+        loop {
+            let n = ready(&mut channel.stream(0), &mut buf)?;
+            if stream && n > 0 {
+                let mut out = io::stdout().lock();
+                out.write_all(&buf[..n])?;
+                out.flush()?;
+            }
+            stdout.extend_from_slice(&buf[..n]);
+            let m = ready(&mut channel.stderr(), &mut buf)?;
+            stderr.extend_from_slice(&buf[..m]);
+
+            if n + m > 0 {
+                heard = Instant::now();
+            } else if channel.eof() {
+                return Ok((stdout, stderr));
+            } else {
+                self.wait(&mut heard, alive, None)?;
+            }
+        }
+    }
+
+    /// Block until the guest sends something or `input` is readable,
+    /// returning whether `input` is. Keepalives are sent meanwhile, and
+    /// the guest is given up on once it leaves ALIVE_COUNT_MAX of them
+    /// unanswered, or `alive` reports it gone.
+    fn wait(
+        &self,
+        heard: &mut Instant,
+        alive: &dyn Fn() -> bool,
+        input: Option<RawFd>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let next = match self.session.keepalive_send() {
+            Ok(next) => next.max(1),
+            Err(e) => match io::Error::from(e) {
+                e if e.kind() == io::ErrorKind::WouldBlock => 1,
+                e => return Err(e.into()),
+            },
+        };
+        let mut fds = vec![self.socket.as_raw_fd()];
+        fds.extend(input);
+        let ready = poll(&fds, Duration::from_secs(next.into()))?;
+
+        if ready[0] {
+            *heard = Instant::now();
+        } else if !alive() {
+            return Err(SshError.into());
+        } else if heard.elapsed() > Duration::from_secs((ALIVE_INTERVAL * ALIVE_COUNT_MAX).into()) {
+            log_error!(
+                "Guest left {} keepalives unanswered, giving up",
+                ALIVE_COUNT_MAX
+            );
+            return Err(SshError.into());
+        }
+        Ok(ready.get(1).copied().unwrap_or(false))
+    }
+
+    /// Upload a file over SFTP, keeping its permission bits so scripts stay
+    /// executable. Guests without an SFTP server still get it over scp.
+    pub fn upload(&self, local: &Path, remote: &str) -> Result<(), Box<dyn Error>> {
+        let mut file = File::open(local)?;
+        let meta = file.metadata()?;
+        let mode = (meta.permissions().mode() & 0o777) as i32;
+        let remote = Path::new(remote);
+
+        match self.session.sftp() {
+            Ok(sftp) => {
+                let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                let mut dst = sftp.open_mode(remote, flags, mode, OpenType::File)?;
+                io::copy(&mut file, &mut dst)?;
+
+                // The mode only applies to new files, not overwritten ones
+                sftp.setstat(
+                    remote,
+                    FileStat {
+                        size: None,
+                        uid: None,
+                        gid: None,
+                        perm: Some(mode as u32),
+                        atime: None,
+                        mtime: None,
+                    },
+                )?;
+            }
+            Err(_) => {
+                let mut dst = self.session.scp_send(remote, mode, meta.len(), None)?;
+                io::copy(&mut file, &mut dst)?;
+                dst.send_eof()?;
+                dst.wait_eof()?;
+                dst.close()?;
+                dst.wait_close()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Interactive shell on a PTY sized like ours. The local terminal is put
+    /// in raw mode for the duration, so control keys reach the guest.
+    pub fn interact(&self, alive: &dyn Fn() -> bool) -> Result<(), Box<dyn Error>> {
+        let (rows, cols) = terminal_size().unwrap_or((24, 80));
+        let term = std::env::var("TERM").unwrap_or_else(|_| "xterm".to_string());
+        let mut channel = self.session.channel_session()?;
+        channel.request_pty(&term, None, Some((cols, rows, 0, 0)))?;
+        channel.shell()?;
+
+        let saved = stty(&["-g"]);
+        stty(&["raw", "-echo"]);
+
+        self.session.set_blocking(false);
+        let res = self.pump(&mut channel, alive);
+        self.session.set_blocking(true);
+
+        if let Some(saved) = saved {
+            stty(&[saved.trim()]);
+        }
+        let _ = channel.close();
+        res
+    }
+
+    /// Forward keystrokes to the shell and its output to ours until it
+    /// exits. Stdin is only read once polled readable, so nothing is left
+    /// reading it after the shell, e.g. from gdb.
+    fn pump(
+        &self,
+        channel: &mut ssh2::Channel,
+        alive: &dyn Fn() -> bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut input = Some(File::open("/dev/stdin")?);
+        let mut out = io::stdout();
+        let mut buf = [0u8; 4096];
+        let mut heard = Instant::now();
+        loop {
+            match ready(channel, &mut buf)? {
+                0 if channel.eof() => return Ok(()),
+                0 => {}
+                n => {
+                    out.write_all(&buf[..n])?;
+                    out.flush()?;
+                    heard = Instant::now();
+                    continue;
+                }
+            }
+
+            let fd = input.as_ref().map(|stdin| stdin.as_raw_fd());
+            if !self.wait(&mut heard, alive, fd)? {
+                continue;
+            }
+            let Some(ref mut stdin) = input else {
+                continue;
+            };
+            let n = stdin.read(&mut buf)?;
+
+            // Writes are few and short, block rather than retry them
+            self.session.set_blocking(true);
+            let res = match n {
+                0 => channel.send_eof().map_err(io::Error::from),
+                n => channel.write_all(&buf[..n]),
+            };
+            self.session.set_blocking(false);
+            res?;
+            if n == 0 {
+                input = None;
+            }
+        }
+    }
+}
+
+/// Read whatever a non-blocking stream has ready, 0 when nothing is
+fn ready(stream: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    match stream.read(buf) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+        res => res,
+    }
+}
+
+/// Wait up to `timeout` for any of `fds` to be readable, returning which are
+fn poll(fds: &[RawFd], timeout: Duration) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    // SAFETY: pollfds holds exactly the number of entries passed along
+    let res = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        return match err.kind() {
+            io::ErrorKind::Interrupted => Ok(vec![false; fds.len()]),
+            _ => Err(err),
+        };
+    }
+    // Hang-ups and errors count as readable, the read reports them
+    Ok(pollfds.iter().map(|p| p.revents != 0).collect())
+}
+
+/// Run stty on our terminal, returning its output
+fn stty(args: &[&str]) -> Option<String> {
+    let tty = File::open("/dev/tty").ok()?;
+    let res = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::from(tty))
+        .output()
+        .ok()?;
+    match res.status.success() {
+        true => Some(String::from_utf8_lossy(&res.stdout).into_owned()),
+        false => None,
+    }
+}
+
+/// Rows and columns of our terminal
+fn terminal_size() -> Option<(u32, u32)> {
+    let size = stty(&["size"])?;
+    let (rows, cols) = size.trim().split_once(' ')?;
+    Some((rows.parse().ok()?, cols.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_openssh_banners() {
+        let parse = |banner| SshVersion::parse(banner);
+        assert_eq!(
+            parse("OpenSSH_9.2p1 Debian-2+deb12u3, OpenSSL 3.0.15 3 Sep 2024"),
+            ("OpenSSH".to_string(), Some((9, 2)))
+        );
+        assert_eq!(
+            parse("OpenSSH_9.10"),
+            ("OpenSSH".to_string(), Some((9, 10)))
+        );
+        assert_eq!(
+            parse("OpenSSH_10.0p2, OpenSSL 3.5.0 8 Apr 2025"),
+            ("OpenSSH".to_string(), Some((10, 0)))
+        );
+        assert_eq!(
+            parse("OpenSSH_for_Windows_8.1p1, LibreSSL 3.0.2"),
+            ("OpenSSH".to_string(), Some((8, 1)))
+        );
+    }
+
+    #[test]
+    fn parses_other_clients() {
+        assert_eq!(
+            SshVersion::parse("Dropbear v2022.83"),
+            ("Dropbear".to_string(), Some((2022, 83)))
+        );
+        assert_eq!(SshVersion::parse("ssh"), ("ssh".to_string(), None));
+        assert_eq!(SshVersion::parse(""), (String::new(), None));
+    }
+
+    /// Reader with nothing available yet, like a non-blocking channel
+    struct Pending;
+
+    impl Read for Pending {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn ready_reads_without_blocking() {
+        let mut buf = [0u8; 8];
+        assert_eq!(ready(&mut Pending, &mut buf).unwrap(), 0);
+        assert_eq!(ready(&mut &b"uname"[..], &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"uname");
+        assert_eq!(ready(&mut &b""[..], &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn polls_for_readable_fds() {
+        use std::os::unix::net::UnixStream;
+
+        let (mut guest, host) = UnixStream::pair().unwrap();
+        let (_idle, stdin) = UnixStream::pair().unwrap();
+        let fds = [host.as_raw_fd(), stdin.as_raw_fd()];
+        assert_eq!(poll(&fds, Duration::ZERO).unwrap(), [false, false]);
+
+        guest.write_all(b"SSH-2.0-OpenSSH_9.2p1\r\n").unwrap();
+        assert_eq!(poll(&fds, Duration::from_secs(1)).unwrap(), [true, false]);
+
+        // A guest that went away reads as ready, the read reports it
+        drop(guest);
+        assert!(poll(&fds[..1], Duration::from_secs(1)).unwrap()[0]);
     }
 }
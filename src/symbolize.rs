@@ -0,0 +1,254 @@
+use crate::builder::ModuleBuilder;
+use crate::qemu::Qemu;
+use crate::KConfig;
+use colored::*;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A single `symbol+0xoff/0xlen [module]` frame from an oops or KASAN report
+#[derive(Debug, PartialEq)]
+struct Frame {
+    symbol: String,
+    offset: u64,
+    module: Option<String>,
+}
+
+impl Frame {
+    /// Find a frame in a line of kernel log output
+    fn parse(line: &str) -> Option<Self> {
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            // Strip prefixes such as "RIP: 0010:"
+            let token = token.rsplit(':').next()?;
+            let Some((symbol, rest)) = token.split_once("+0x") else {
+                continue;
+            };
+            let Some((offset, _len)) = rest.split_once("/0x") else {
+                continue;
+            };
+            let Ok(offset) = u64::from_str_radix(offset, 16) else {
+                continue;
+            };
+            // Kernels with CONFIG_STACKTRACE_BUILD_ID print "[name build-id]"
+            let module = tokens
+                .next()
+                .and_then(|t| t.strip_prefix('['))
+                .map(|t| t.trim_end_matches(']').to_string());
+            return Some(Self {
+                symbol: symbol.to_string(),
+                offset,
+                module,
+            });
+        }
+        None
+    }
+}
+
+/// An ELF object with its function symbols
+struct Object {
+    path: PathBuf,
+    relocatable: bool,
+    symbols: HashMap<String, (u64, String)>,
+}
+
+impl Object {
+    /// Load the symbol table with objdump
+    fn load(path: &Path, relocatable: bool) -> Result<Self, Box<dyn Error>> {
+        let res = Command::new("objdump").arg("-t").arg(path).output()?;
+        if !res.status.success() {
+            return Err(format!("objdump failed on {:?}", path).into());
+        }
+
+        // <value> <flags> <section>\t<size> <name>
+        let mut symbols = HashMap::new();
+        for line in String::from_utf8_lossy(&res.stdout).lines() {
+            let Some((head, tail)) = line.split_once('\t') else {
+                continue;
+            };
+            let mut head = head.split_whitespace();
+            let value = head.next().and_then(|v| u64::from_str_radix(v, 16).ok());
+            let section = head.last();
+            let name = tail.split_whitespace().last();
+            if let (Some(value), Some(section), Some(name)) = (value, section, name) {
+                symbols.insert(name.to_string(), (value, section.to_string()));
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            relocatable,
+            symbols,
+        })
+    }
+
+    /// Resolve a frame to file:line with addr2line. Relocatable
+    /// objects address each section from zero, so the section is
+    /// passed along.
+    fn resolve(&self, frame: &Frame) -> Option<String> {
+        let (value, section) = self.symbols.get(&frame.symbol)?;
+        let mut addr2line = Command::new("addr2line");
+        addr2line.args(["-f", "-i", "-p", "-e"]).arg(&self.path);
+        if self.relocatable {
+            addr2line.args(["-j", section]);
+        }
+        let res = addr2line
+            .arg(format!("{:#x}", value + frame.offset))
+            .output()
+            .ok()?;
+        let out = String::from_utf8_lossy(&res.stdout).trim().to_string();
+        match res.status.success() && !out.contains("??") {
+            true => Some(out),
+            false => None,
+        }
+    }
+}
+
+/// Resolves stack frames of the modules and the kernel
+pub struct Symbolizer {
+    modules: HashMap<String, Object>,
+    vmlinux: Option<Object>,
+}
+
+impl Symbolizer {
+    /// Load symbols from the built modules and optional vmlinux,
+    /// any being unavailable only limits what is decoded.
+    pub fn new(kos: &[PathBuf], vmlinux: &Option<String>) -> Self {
+        let modules = kos
+            .iter()
+            .filter_map(|ko| {
+                let name = ModuleBuilder::modname(ko)?;
+                Object::load(ko, true)
+                    .map_err(|e| log_error!("Can't load symbols of {}: {}", name, e))
+                    .ok()
+                    .map(|o| (name, o))
+            })
+            .collect();
+        let vmlinux = vmlinux.as_ref().and_then(|path| {
+            Object::load(Path::new(path), false)
+                .map_err(|e| log_error!("Can't load vmlinux symbols: {}", e))
+                .ok()
+        });
+        Self { modules, vmlinux }
+    }
+
+    /// Decode every frame found in the log, skipping repeats
+    pub fn decode(&self, log: &str) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut res = Vec::new();
+        for line in log.lines() {
+            let Some(frame) = Frame::parse(line) else {
+                continue;
+            };
+            let object = match frame.module {
+                Some(ref m) => self.modules.get(m),
+                None => self.vmlinux.as_ref(),
+            };
+            let Some(location) = object.and_then(|o| o.resolve(&frame)) else {
+                continue;
+            };
+            let decoded = format!("{} => {}", line.trim(), location);
+            if seen.insert(decoded.clone()) {
+                res.push(decoded);
+            }
+        }
+        res
+    }
+}
+
+/// Collect the console and dmesg of a failed guest, and write
+/// decoded stack traces to trace.txt in the run directory.
+pub fn report(handle: &Qemu, kernel: &KConfig, kos: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let mut log = fs::read_to_string(handle.rundir().join("console.log")).unwrap_or_default();
+
+    // A panicked guest won't answer, the console has it all anyway
+    if !handle.panicked() {
+        if let Ok(dmesg) = handle.runcmd_output("dmesg") {
+            fs::write(handle.rundir().join("dmesg.log"), &dmesg)?;
+            log.push_str(&dmesg);
+        }
+    }
+
+    let traces = Symbolizer::new(kos, &kernel.vmlinux).decode(&log);
+    if traces.is_empty() {
+        return Ok(());
+    }
+
+    log_error!("Decoded stack trace for {}:", kernel.version);
+    for line in traces.iter() {
+        println!("    {}", line);
+    }
+    let path = handle.rundir().join("trace.txt");
+    fs::write(&path, traces.join("\n") + "\n")?;
+    log_status!("Decoded trace saved to {:?}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(symbol: &str, offset: u64, module: Option<&str>) -> Option<Frame> {
+        Some(Frame {
+            symbol: symbol.to_string(),
+            offset,
+            module: module.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn parses_oops_frames() {
+        assert_eq!(
+            Frame::parse("[   12.345678] RIP: 0010:example_init+0x2a/0x80 [example]"),
+            frame("example_init", 0x2a, Some("example"))
+        );
+        assert_eq!(
+            Frame::parse("[   12.345690]  ? example_helper+0x10/0x20 [example]"),
+            frame("example_helper", 0x10, Some("example"))
+        );
+        assert_eq!(
+            Frame::parse("[   12.345691]  do_one_initcall+0x46/0x1d0"),
+            frame("do_one_initcall", 0x46, None)
+        );
+    }
+
+    #[test]
+    fn parses_kasan_reports() {
+        assert_eq!(
+            Frame::parse(
+                "[   20.123456] BUG: KASAN: slab-out-of-bounds in example_write+0x8c/0xa0 [example]"
+            ),
+            frame("example_write", 0x8c, Some("example"))
+        );
+        assert_eq!(
+            Frame::parse("[   20.123470]  kasan_report+0xb9/0xf0"),
+            frame("kasan_report", 0xb9, None)
+        );
+    }
+
+    #[test]
+    fn parses_older_and_build_id_formats() {
+        assert_eq!(
+            Frame::parse(" [<ffffffffc0000010>] example_init+0x10/0x1000 [example]"),
+            frame("example_init", 0x10, Some("example"))
+        );
+        assert_eq!(
+            Frame::parse(
+                " example_init+0x2a/0x80 [example 2a9f3d55c6e3a0f1b7d1e0a3c4b5d6e7f8091a2b]"
+            ),
+            frame("example_init", 0x2a, Some("example"))
+        );
+    }
+
+    #[test]
+    fn ignores_lines_without_frames() {
+        assert_eq!(Frame::parse("[   12.345680] Call Trace:"), None);
+        assert_eq!(Frame::parse("[   12.345681]  <TASK>"), None);
+        assert_eq!(
+            Frame::parse("[   12.345600] Modules linked in: example(O+)"),
+            None
+        );
+    }
+}
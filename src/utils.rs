@@ -1,3 +1,5 @@
+use std::path::Path;
+
 #[macro_export]
 macro_rules! log_status {
     ($($arg:tt)*) => (println!("{} {}", "[*]".blue().bold(), format_args!($($arg)*)));
@@ -18,9 +20,54 @@ macro_rules! prompt {
     ($($arg:tt)*) => (format!("{} {}", "[?]".yellow().bold(), format_args!($($arg)*)));
 }
 
+/// Build outputs and bookkeeping that aren't part of a module's sources
+pub fn is_build_output(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    name.starts_with('.')
+        || name.ends_with(".mod.c")
+        || matches!(
+            name,
+            "nixmodule-runs" | "target" | "Module.symvers" | "compile_commands.json"
+        )
+        || matches!(ext, "o" | "ko" | "cmd" | "mod" | "order" | "a")
+}
+
+/// Quote an argument for sh, leaving plain words as they are
+pub fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c));
+    match plain {
+        true => arg.to_string(),
+        false => format!("'{}'", arg.replace('\'', "'\\''")),
+    }
+}
+
 /// Print output by line
 pub fn print_output(out: &str) {
     for line in out.split('\n') {
         println!("{}", line);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_only_when_needed() {
+        assert_eq!(shell_quote("-oConnectTimeout=10"), "-oConnectTimeout=10");
+        assert_eq!(shell_quote("root@localhost"), "root@localhost");
+        assert_eq!(shell_quote("/my keys/id_rsa"), "'/my keys/id_rsa'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
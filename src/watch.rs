@@ -0,0 +1,94 @@
+use crate::utils::is_build_output;
+use colored::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+/// How often the source tree is scanned
+const POLL: Duration = Duration::from_millis(500);
+
+/// Polls a module's source tree for modifications
+pub struct Watcher {
+    root: PathBuf,
+    skip: Vec<PathBuf>,
+    mtimes: HashMap<PathBuf, SystemTime>,
+}
+
+impl Watcher {
+    /// Watch `root`, skipping directories such as the run output
+    pub fn new(root: &Path, skip: &[&Path]) -> Self {
+        let mut res = Self {
+            root: root.to_path_buf(),
+            skip: skip
+                .iter()
+                .filter_map(|p| fs::canonicalize(p).ok())
+                .collect(),
+            mtimes: HashMap::new(),
+        };
+        res.mtimes = res.scan();
+        res
+    }
+
+    /// Modification times of every watched file
+    fn scan(&self) -> HashMap<PathBuf, SystemTime> {
+        let mut res = HashMap::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if is_build_output(&path) || self.skip.iter().any(|s| path.starts_with(s)) {
+                    continue;
+                }
+                match entry.metadata() {
+                    Ok(meta) if meta.is_dir() => dirs.push(path),
+                    Ok(meta) => {
+                        res.insert(path, meta.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        res
+    }
+
+    /// Block until the tree changes and has settled for one poll
+    /// interval, returning the files that changed.
+    pub fn wait(&mut self) -> Vec<PathBuf> {
+        log_status!("Watching {:?} for changes...", self.root);
+        let mut current = self.scan();
+        while current == self.mtimes {
+            sleep(POLL);
+            current = self.scan();
+        }
+
+        // Debounce editors writing several files at once
+        loop {
+            sleep(POLL);
+            let next = self.scan();
+            if next == current {
+                break;
+            }
+            current = next;
+        }
+
+        let mut changed: Vec<PathBuf> = current
+            .iter()
+            .filter(|(path, mtime)| self.mtimes.get(*path) != Some(mtime))
+            .map(|(path, _)| path.clone())
+            .chain(
+                self.mtimes
+                    .keys()
+                    .filter(|path| !current.contains_key(*path))
+                    .cloned(),
+            )
+            .collect();
+        changed.sort();
+        self.mtimes = current;
+        changed
+    }
+}